@@ -0,0 +1,331 @@
+//! Generates the linker script and the `layout` module of the selected board
+//! from a single description of its memory layout, so the two can not drift
+//! apart. Every layout is checked here, a bad one fails the build.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Where everything goes on one board
+struct Layout {
+    /// Where the firmware loads the image and starts executing it
+    load_addr: u64,
+    /// How far below `load_addr` the bootloader is linked
+    bootloader_offset: u64,
+    /// Top of the stack until the bootloader has relocated itself
+    stack_top: u64,
+    stack_size: u64,
+    /// Stacks of the secondary cores, carved out of the bottom of the main one
+    secondary_stack_size: u64,
+    cores: u64,
+    /// Alignment of the relocated copy of the bootloader
+    relocation_align: u64,
+    /// Start and end of the peripherals
+    peripherals: (u64, u64),
+    /// Start and end of the ARM local peripherals, which are part of
+    /// `peripherals`
+    local_peripherals: (u64, u64),
+    /// Memory the kernel must leave alone, e.g. the firmware's spin table
+    reserved: &'static [(u64, u64)],
+}
+
+const RPI3: Layout = Layout {
+    load_addr: 0x8_0000,
+    bootloader_offset: 0,
+    stack_top: 0x8_0000,
+    stack_size: 0x1_0000,
+    secondary_stack_size: 0x400,
+    cores: 4,
+    relocation_align: 0x1_0000,
+    peripherals: (0x3F00_0000, 0x4004_0000),
+    local_peripherals: (0x4000_0000, 0x4004_0000),
+    reserved: &[(0, 0x1000)],
+};
+
+const RPI4: Layout = Layout {
+    load_addr: 0x8_0000,
+    bootloader_offset: 0,
+    stack_top: 0x8_0000,
+    stack_size: 0x1_0000,
+    secondary_stack_size: 0x400,
+    cores: 4,
+    relocation_align: 0x1_0000,
+    peripherals: (0xFE00_0000, 0xFF85_0000),
+    local_peripherals: (0xFF80_0000, 0xFF85_0000),
+    reserved: &[(0, 0x1000)],
+};
+
+const PAGE_SIZE: u64 = 0x1000;
+
+const LINKER_SCRIPT: &str = "ENTRY(_start)
+
+SECTIONS
+{
+    /* Generated by build.rs, edit the layout there */
+    . = {LINK_ADDR};
+
+    __code = .;
+    .text ALIGN(8):
+    {
+        *(.text._start) *(.text*)
+    }
+
+    .rodata ALIGN(8) :
+    {
+        *(.rodata .rodata.*) *(.got)
+    }
+
+    /* What the bootloader needs to relocate itself, see relocation.rs */
+    .rela.dyn ALIGN(8) :
+    {
+        __rela_start = .;
+        *(.rela.dyn .rela.*)
+        __rela_end = .;
+    }
+
+    .data ALIGN(8):
+    {
+        *(.data .data.*)
+    }
+
+    /*Align to 8 byte boundary */
+    .bss ALIGN(8):
+    {
+        __bss_start = .;
+        *(.bss .bss.*);
+        __bss_end = .;
+    }
+    . = ALIGN(8);
+    __end = .;
+
+    /DISCARD/ : { *(.comment*) }
+}
+";
+
+fn overlaps(a: (u64, u64), b: (u64, u64)) -> bool {
+    a.0 < b.1 && b.0 < a.1
+}
+
+impl Layout {
+    fn link_addr(&self) -> u64 {
+        self.load_addr - self.bootloader_offset
+    }
+
+    fn stack(&self) -> (u64, u64) {
+        (self.stack_top - self.stack_size, self.stack_top)
+    }
+
+    /// Initial stack pointer of each core, the secondary stacks sit at the
+    /// bottom of core 0's
+    fn stack_tops(&self) -> Vec<u64> {
+        (0..self.cores)
+            .map(|core| match core {
+                0 => self.stack_top,
+                _ => self.stack().0 + core * self.secondary_stack_size,
+            })
+            .collect()
+    }
+
+    fn check(&self, board: &str) {
+        let fail = |what: &str| panic!("bad memory layout for {}: {}", board, what);
+
+        if self.load_addr % PAGE_SIZE != 0 || self.bootloader_offset % PAGE_SIZE != 0 {
+            fail("the load address and bootloader offset must be page aligned");
+        }
+        if self.bootloader_offset > self.load_addr {
+            fail("the bootloader offset reaches below address 0");
+        }
+        if self.stack_top % 16 != 0
+            || self.stack_size % 16 != 0
+            || self.secondary_stack_size % 16 != 0
+        {
+            fail("stacks must be 16 byte aligned");
+        }
+        if self.stack_size > self.stack_top {
+            fail("the stack reaches below address 0");
+        }
+        if self.stack_top > self.link_addr() {
+            fail("the stack overlaps the bootloader");
+        }
+        // Core 0 keeps at least half of the stack to itself
+        if self.cores * self.secondary_stack_size > self.stack_size / 2 {
+            fail("the secondary stacks take up too much of the stack");
+        }
+        if !self.relocation_align.is_power_of_two() || self.relocation_align < PAGE_SIZE {
+            fail("the relocation alignment must be a power of two of at least a page");
+        }
+        if self.peripherals.0 >= self.peripherals.1 {
+            fail("the peripherals are empty");
+        }
+        if self.local_peripherals.0 >= self.local_peripherals.1
+            || self.local_peripherals.0 < self.peripherals.0
+            || self.local_peripherals.1 > self.peripherals.1
+        {
+            fail("the local peripherals must be a part of the peripherals");
+        }
+
+        for (i, &region) in self.reserved.iter().enumerate() {
+            if region.0 >= region.1 {
+                fail("a reserved region is empty");
+            }
+            if overlaps(region, (self.stack().0, self.load_addr + 1)) {
+                fail("a reserved region overlaps the bootloader or its stack");
+            }
+            if overlaps(region, self.peripherals) {
+                fail("a reserved region overlaps the peripherals");
+            }
+            if self.reserved[..i]
+                .iter()
+                .any(|&other| overlaps(region, other))
+            {
+                fail("reserved regions overlap each other");
+            }
+        }
+    }
+
+    fn linker_script(&self) -> String {
+        LINKER_SCRIPT.replace("{LINK_ADDR}", &format!("{:#x}", self.link_addr()))
+    }
+
+    fn constants(&self) -> String {
+        let reserved: Vec<String> = self
+            .reserved
+            .iter()
+            .map(|&(start, end)| format!("({:#x}, {:#x})", start, end))
+            .collect();
+
+        let stack_tops: Vec<String> = self
+            .stack_tops()
+            .iter()
+            .map(|top| format!("{:#x}", top))
+            .collect();
+
+        let mut out = String::from("// Generated by build.rs, edit the layout there\n\n");
+        let mut constant = |doc: &str, name: &str, ty: &str, value: String| {
+            out += &format!(
+                "/// {}\n#[allow(dead_code)]\npub const {}: {} = {};\n",
+                doc, name, ty, value
+            );
+        };
+
+        constant(
+            "Where the firmware loads the image and starts it",
+            "LOAD_ADDR",
+            "u64",
+            format!("{:#x}", self.load_addr),
+        );
+        constant(
+            "Where the bootloader is linked",
+            "LINK_ADDR",
+            "u64",
+            format!("{:#x}", self.link_addr()),
+        );
+        constant(
+            "Top of the stack until the bootloader has relocated itself",
+            "STACK_TOP",
+            "u64",
+            format!("{:#x}", self.stack_top),
+        );
+        constant(
+            "Size of the stack, secondary stacks included",
+            "STACK_SIZE",
+            "u64",
+            format!("{:#x}", self.stack_size),
+        );
+        constant(
+            "Stack of each secondary core",
+            "SECONDARY_STACK_SIZE",
+            "u64",
+            format!("{:#x}", self.secondary_stack_size),
+        );
+        constant(
+            "Number of cores",
+            "CORES",
+            "usize",
+            format!("{}", self.cores),
+        );
+        constant(
+            "Stack pointer of each core until the bootloader has relocated itself",
+            "STACK_TOPS",
+            "[u64; CORES]",
+            format!("[{}]", stack_tops.join(", ")),
+        );
+        constant(
+            "Alignment of the relocated bootloader",
+            "RELOCATION_ALIGN",
+            "u64",
+            format!("{:#x}", self.relocation_align),
+        );
+        constant(
+            "Start and end of the peripherals",
+            "PERIPHERALS",
+            "(u64, u64)",
+            format!("({:#x}, {:#x})", self.peripherals.0, self.peripherals.1),
+        );
+        constant(
+            "Start and end of the ARM local peripherals",
+            "LOCAL_PERIPHERALS",
+            "(u64, u64)",
+            format!(
+                "({:#x}, {:#x})",
+                self.local_peripherals.0, self.local_peripherals.1
+            ),
+        );
+        constant(
+            "Start and end of memory the kernel must leave alone",
+            "RESERVED",
+            "&[(u64, u64)]",
+            format!("&[{}]", reserved.join(", ")),
+        );
+
+        out
+    }
+}
+
+/// The most verbose log level that gets printed, from `RASPBOOTIN_LOG`
+fn log_level() -> String {
+    let level = env::var("RASPBOOTIN_LOG").unwrap_or_else(|_| String::from("info"));
+    let level = match level.as_str() {
+        "error" => "Error",
+        "warn" => "Warn",
+        "info" => "Info",
+        "debug" => "Debug",
+        _ => panic!("RASPBOOTIN_LOG must be one of error, warn, info or debug"),
+    };
+
+    format!(
+        "// Generated by build.rs\n\npub const MAX_LEVEL: Level = Level::{};\n",
+        level
+    )
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=RASPBOOTIN_LOG");
+
+    let boards = [("bsp_rpi3", "rpi3", RPI3), ("bsp_rpi4", "rpi4", RPI4)];
+    let mut selected = boards.iter().filter(|(feature, _, _)| {
+        env::var_os(format!("CARGO_FEATURE_{}", feature.to_uppercase())).is_some()
+    });
+
+    let (_, board, layout) = match (selected.next(), selected.next()) {
+        (Some(board), None) => board,
+        _ => panic!("select exactly one board with --features bsp_rpi3 or --features bsp_rpi4"),
+    };
+
+    // Every layout gets checked, not just the one being built
+    for (_, board, layout) in boards.iter() {
+        layout.check(board);
+    }
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let script = out.join(format!("{}.ld", board));
+    fs::write(&script, layout.linker_script()).unwrap();
+    fs::write(out.join("layout.rs"), layout.constants()).unwrap();
+    fs::write(out.join("log_level.rs"), log_level()).unwrap();
+
+    println!("cargo:rustc-link-arg=-T{}", script.display());
+    // A PIE keeps the relocations the bootloader applies to itself
+    println!("cargo:rustc-link-arg=-pie");
+    println!("cargo:rustc-link-arg=--no-dynamic-linker");
+}
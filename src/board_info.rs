@@ -0,0 +1,99 @@
+//! What the board and its firmware tell us about themselves, sent to the host
+//! after the handshake when it asks with `Command::BoardInfo`, so it can log
+//! what a test ran on.
+//!
+//! The record is a list of little endian words: `VERSION`, the number of words
+//! that follow, a mask with a bit set for every field the firmware answered,
+//! and then the fields in the order of `Field`. New fields are only ever added
+//! at the end, so a host can skip whatever it does not know about.
+
+use crate::bsp::mbox::Mbox;
+use crate::bsp::Uart;
+use crate::protocol;
+
+pub const VERSION: u32 = 1;
+
+/// The fields of the record, each one bit in the mask
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum Field {
+    BoardModel,
+    BoardRevision,
+    BoardSerial,
+    BoardMAC,
+    FirmwareRevision,
+    ARMMemory,
+    VCMemory,
+}
+
+pub struct BoardInfo {
+    pub board_model: Option<u32>,
+    pub board_revision: Option<u32>,
+    pub board_serial: Option<u64>,
+    pub board_mac: Option<u64>,
+    pub firmware_revision: Option<u32>,
+    /// Base address and size
+    pub arm_memory: Option<(u32, u32)>,
+    /// Base address and size
+    pub vc_memory: Option<(u32, u32)>,
+}
+
+impl BoardInfo {
+    pub fn collect(mbox: &mut Mbox) -> BoardInfo {
+        BoardInfo {
+            board_model: mbox.get_board_model().ok(),
+            board_revision: mbox.get_board_revision().ok(),
+            board_serial: mbox.get_board_serial().ok(),
+            board_mac: mbox.get_board_mac().ok(),
+            firmware_revision: mbox.get_firmware_revision().ok(),
+            arm_memory: mbox.get_arm_memory().ok(),
+            vc_memory: mbox.get_vc_memory().ok(),
+        }
+    }
+
+    fn mask(&self) -> u32 {
+        let fields = [
+            (self.board_model.is_some(), Field::BoardModel),
+            (self.board_revision.is_some(), Field::BoardRevision),
+            (self.board_serial.is_some(), Field::BoardSerial),
+            (self.board_mac.is_some(), Field::BoardMAC),
+            (self.firmware_revision.is_some(), Field::FirmwareRevision),
+            (self.arm_memory.is_some(), Field::ARMMemory),
+            (self.vc_memory.is_some(), Field::VCMemory),
+        ];
+
+        fields
+            .iter()
+            .filter(|(present, _)| *present)
+            .fold(0, |mask, (_, field)| mask | (1 << *field as u32))
+    }
+
+    /// Send the record to the host
+    pub fn send(&self, uart: &Uart) {
+        let serial = self.board_serial.unwrap_or(0);
+        let mac = self.board_mac.unwrap_or(0);
+        let arm_memory = self.arm_memory.unwrap_or((0, 0));
+        let vc_memory = self.vc_memory.unwrap_or((0, 0));
+
+        let words = [
+            self.mask(),
+            self.board_model.unwrap_or(0),
+            self.board_revision.unwrap_or(0),
+            serial as u32,
+            (serial >> 32) as u32,
+            mac as u32,
+            (mac >> 32) as u32,
+            self.firmware_revision.unwrap_or(0),
+            arm_memory.0,
+            arm_memory.1,
+            vc_memory.0,
+            vc_memory.1,
+        ];
+
+        protocol::send_u32(uart, VERSION);
+        protocol::send_u32(uart, words.len() as u32);
+        for &word in words.iter() {
+            protocol::send_u32(uart, word);
+        }
+    }
+}
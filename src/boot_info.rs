@@ -0,0 +1,281 @@
+//! Boot information handed to the loaded kernel, so it does not have to ask
+//! the firmware for everything again.
+//!
+//! The kernel gets the address of the structure in `x1`, and in `x0` as well
+//! when there is no device tree, see `bsp::jump_to_kernel`. It starts with four
+//! little endian words: `MAGIC`, `VERSION`, the total size in bytes and a
+//! reserved word. A list of tags follows, each one a word with the `Tag`, a
+//! word with the size of the payload in bytes and the payload itself, padded
+//! to a multiple of 8 bytes. The list ends with `Tag::End`. Tags are only
+//! ever added, so a kernel should skip the ones it does not know about. A tag
+//! that does not fit in the buffer is left out.
+//!
+//! The structure lives in the bootloader's memory, which is listed as
+//! reserved in the memory map. A kernel that wants to reuse that memory has to
+//! copy whatever it needs out of it first.
+
+use crate::board_info::BoardInfo;
+use crate::bsp;
+use crate::fdt::Fdt;
+use crate::layout;
+use crate::loader::Image;
+
+/// "RBIB" in ASCII
+pub const MAGIC: u32 = 0x4249_4252;
+pub const VERSION: u32 = 1;
+
+// Room for the header, every tag and a full command line
+const BUFFER_LEN: usize = 1024;
+
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum Tag {
+    End = 0,
+    /// A list of regions: base and size as 64-bit values, then the
+    /// `MemoryType` and a padding word. Reserved regions can overlap usable
+    /// ones and take precedence.
+    MemoryMap = 1,
+    /// Board model and revision
+    Board = 2,
+    /// 64-bit board serial number
+    Serial = 3,
+    /// 64-bit MAC address
+    MAC = 4,
+    FirmwareRevision = 5,
+    /// UART number (0 for the PL011, 1 for the mini UART), UART clock in Hz, baud rate
+    Uart = 6,
+    /// The command line from the firmware, not terminated
+    CommandLine = 7,
+    /// A list of images: 64-bit load address, size and the id the host gave it
+    Images = 8,
+    /// Counter frequency, then the 64-bit counter values when the bootloader
+    /// started and when it handed over to the kernel
+    Timestamps = 9,
+    /// 64-bit address and magic to jump back into the bootloader with
+    Reentry = 10,
+    /// The exception level the kernel was entered at
+    ExceptionLevel = 11,
+    /// 64-bit address of the device tree the firmware passed us
+    DeviceTree = 12,
+}
+
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum MemoryType {
+    /// Free for the kernel to use
+    Usable = 1,
+    /// Used by the firmware or the bootloader
+    Reserved = 2,
+    /// Peripherals
+    Device = 3,
+}
+
+/// What the kernel is told about the UART we talked to the host through
+pub struct UartConfig {
+    pub number: u32,
+    pub clock: u32,
+    pub baud_rate: u32,
+}
+
+// Not on the stack, it has to survive the jump into the kernel
+static mut BUFFER: [u32; BUFFER_LEN] = [0; BUFFER_LEN];
+
+// Words `Tag::End` takes, the buffer always keeps room for them
+const END_TAG_LEN: usize = 2;
+
+struct Builder {
+    buffer: &'static mut [u32],
+    len: usize,
+    /// Where the tag being pushed starts
+    tag_start: usize,
+    /// Some of the tag being pushed did not fit, `end_tag` drops all of it
+    overflowed: bool,
+}
+
+impl Builder {
+    fn new(buffer: &'static mut [u32]) -> Builder {
+        Builder {
+            buffer,
+            len: 0,
+            tag_start: 0,
+            overflowed: false,
+        }
+    }
+
+    fn push(&mut self, word: u32) {
+        if self.len + END_TAG_LEN < self.buffer.len() {
+            self.buffer[self.len] = word;
+            self.len += 1;
+        } else {
+            self.overflowed = true;
+        }
+    }
+
+    fn push_u64(&mut self, value: u64) {
+        self.push(value as u32);
+        self.push((value >> 32) as u32);
+    }
+
+    /// Start a tag with a payload of `size` bytes, the payload is pushed by
+    /// the caller and `end_tag` pads it
+    fn start_tag(&mut self, tag: Tag, size: usize) {
+        self.tag_start = self.len;
+        self.push(tag as u32);
+        self.push(size as u32);
+    }
+
+    fn end_tag(&mut self) {
+        if self.len % 2 != 0 {
+            self.push(0);
+        }
+
+        // Half a tag would throw a kernel off, so leave it out altogether
+        if self.overflowed {
+            self.len = self.tag_start;
+            self.overflowed = false;
+        }
+    }
+
+    /// End the list and fill in the total size
+    fn finish(self) -> &'static [u32] {
+        self.buffer[self.len] = Tag::End as u32;
+        self.buffer[self.len + 1] = 0;
+        let len = self.len + END_TAG_LEN;
+
+        self.buffer[2] = (len * 4) as u32;
+        let buffer: &'static [u32] = self.buffer;
+        &buffer[..len]
+    }
+
+    fn tag(&mut self, tag: Tag, payload: &[u32]) {
+        self.start_tag(tag, payload.len() * 4);
+        for &word in payload {
+            self.push(word);
+        }
+        self.end_tag();
+    }
+
+    fn tag_bytes(&mut self, tag: Tag, payload: &[u8]) {
+        self.start_tag(tag, payload.len());
+        for chunk in payload.chunks(4) {
+            let word = chunk
+                .iter()
+                .enumerate()
+                .fold(0, |word, (i, &c)| word | (u32::from(c) << (i * 8)));
+            self.push(word);
+        }
+        self.end_tag();
+    }
+
+    fn memory_region(&mut self, (start, end): (u64, u64), kind: MemoryType) {
+        self.push_u64(start);
+        self.push_u64(end - start);
+        self.push(kind as u32);
+        self.push(0);
+    }
+}
+
+pub struct BootInfo<'a> {
+    pub board: &'a BoardInfo,
+    pub command_line: &'a [u8],
+    pub uart: UartConfig,
+    pub image: &'a Image,
+    pub device_tree: Option<&'a Fdt>,
+    pub exception_level: u32,
+    /// Counter frequency and value when the bootloader started
+    pub timer_frequency: u64,
+    pub start_ticks: u64,
+}
+
+impl<'a> BootInfo<'a> {
+    /// Fill in the structure, returns it so its address can go in `x1`
+    pub fn write(&self, handoff_ticks: u64) -> &'static [u32] {
+        let mut b = Builder::new(unsafe { &mut BUFFER[..] });
+
+        b.push(MAGIC);
+        b.push(VERSION);
+        // Total size, filled in at the end
+        b.push(0);
+        b.push(0);
+
+        let range = |(base, size): (u32, u32)| (u64::from(base), u64::from(base) + u64::from(size));
+        let image = (
+            self.image.addr() as u64,
+            self.image.addr() as u64 + u64::from(self.image.size()),
+        );
+        let regions = [
+            self.board
+                .arm_memory
+                .map(|r| (range(r), MemoryType::Usable)),
+            self.board
+                .vc_memory
+                .map(|r| (range(r), MemoryType::Reserved)),
+            Some((bsp::bootloader_memory(), MemoryType::Reserved)),
+            Some((image, MemoryType::Reserved)),
+            self.device_tree.map(|fdt| {
+                let start = fdt.addr() as u64;
+                ((start, start + fdt.size() as u64), MemoryType::Reserved)
+            }),
+            Some((bsp::PERIPHERAL_MEMORY, MemoryType::Device)),
+        ];
+
+        let count = regions.iter().flatten().count() + layout::RESERVED.len();
+        b.start_tag(Tag::MemoryMap, count * 24);
+        for &(range, kind) in regions.iter().flatten() {
+            b.memory_region(range, kind);
+        }
+        for &range in layout::RESERVED {
+            b.memory_region(range, MemoryType::Reserved);
+        }
+        b.end_tag();
+
+        if let (Some(model), Some(revision)) = (self.board.board_model, self.board.board_revision) {
+            b.tag(Tag::Board, &[model, revision]);
+        }
+        if let Some(serial) = self.board.board_serial {
+            b.tag(Tag::Serial, &[serial as u32, (serial >> 32) as u32]);
+        }
+        if let Some(mac) = self.board.board_mac {
+            b.tag(Tag::MAC, &[mac as u32, (mac >> 32) as u32]);
+        }
+        if let Some(revision) = self.board.firmware_revision {
+            b.tag(Tag::FirmwareRevision, &[revision]);
+        }
+
+        b.tag(
+            Tag::Uart,
+            &[self.uart.number, self.uart.clock, self.uart.baud_rate],
+        );
+
+        if !self.command_line.is_empty() {
+            b.tag_bytes(Tag::CommandLine, self.command_line);
+        }
+
+        b.start_tag(Tag::Images, 16);
+        b.push_u64(self.image.addr() as u64);
+        b.push(self.image.size());
+        b.push(self.image.id());
+        b.end_tag();
+
+        b.start_tag(Tag::Timestamps, 24);
+        b.push_u64(self.timer_frequency);
+        b.push_u64(self.start_ticks);
+        b.push_u64(handoff_ticks);
+        b.end_tag();
+
+        b.start_tag(Tag::Reentry, 16);
+        b.push_u64(bsp::reentry_addr());
+        b.push_u64(bsp::REENTRY_MAGIC);
+        b.end_tag();
+
+        b.tag(Tag::ExceptionLevel, &[self.exception_level]);
+
+        if let Some(fdt) = self.device_tree {
+            b.start_tag(Tag::DeviceTree, 8);
+            b.push_u64(fdt.addr() as u64);
+            b.end_tag();
+        }
+
+        b.finish()
+    }
+}
@@ -1,91 +1,205 @@
 //! Board Support Package for the Raspberry Pi 3.
 
 mod panic_wait;
+mod relocation;
+mod spin_table;
 
-const MMIO_BASE: u32 = 0x3F00_0000;
+// The peripherals start with the VideoCore ones, the ARM local ones follow
+const MMIO_BASE: u32 = layout::PERIPHERALS.0 as u32;
+const LOCAL_BASE: u32 = layout::LOCAL_PERIPHERALS.0 as u32;
 
+pub mod cache;
+pub mod exception;
 mod gpio;
+mod handoff;
+mod intc;
+mod local_intc;
+mod serial;
+pub use handoff::{handoff_level, jump_to_kernel, ExceptionLevel, DEFAULT_HANDOFF};
+pub use serial::{Receive, RxError, SoftwareFlow};
+pub use spin_table::release_addr;
+#[cfg(not(feature = "mini_uart"))]
 mod uart0;
-pub use uart0::Uart;
+#[cfg(not(feature = "mini_uart"))]
+pub use uart0::{Uart, BAUD_RATE, UART_NUMBER};
+#[cfg(feature = "mini_uart")]
+mod uart1;
+#[cfg(feature = "mini_uart")]
+pub use uart1::{Uart, BAUD_RATE, UART_NUMBER};
 pub mod mbox;
+pub mod mmu;
+mod timer;
+pub use timer::Timer;
+mod watchdog;
+pub use watchdog::Watchdog;
 
-use core::sync::atomic::{compiler_fence, Ordering};
-use cortex_a::{asm, regs::*};
+use crate::fdt::Fdt;
+use crate::layout::{self, RELOCATION_ALIGN, STACK_SIZE};
+use core::ptr::{read_volatile, write_volatile};
+use cortex_a::regs::*;
 
-// I really do not like this situation currently, This offset is stored not only here but also in the linker script
-// I would like a way to get these values to be the same so you don't run into MAJOR issues chain loading
-const RASPBOOTIN_OFFSET: u64 = 0x1000;
-// Hard coded value of where the VideoCore dumps the kernel8.img file regardless of where it wants to be loaded
-const RASP_KERN_START: u64 = 0x80_000;
-// We are moving out stack back to our raspbootin area
-const STACK_START: u64 = 0x80_000 - (RASPBOOTIN_OFFSET as u64);
+const CORE_MASK: u64 = 0x3;
 
-/// The entry of the `kernel` binary.
-///
-/// The function must be named `_start`, because the linker is looking for this
-/// exact name.
+global_asm!(include_str!("rpi3/start.S"));
+
+// Initial stack pointer of each core, `_start` picks its own before there is a
+// stack. They move along with the image in `relocate`.
+#[no_mangle]
+static mut STACK_TOPS: [u64; layout::CORES] = layout::STACK_TOPS;
+
+extern "C" {
+    static mut __code: u64;
+    static mut __end: u64;
+
+    /// The entry of the `kernel` binary, in `start.S`.
+    ///
+    /// The function must be named `_start`, because the linker is looking for
+    /// this exact name. It sets up the stack of the core it runs on and only
+    /// then calls into Rust, which keeps the compiler from touching a stack
+    /// that is not there yet.
+    ///
+    /// # Relocation
+    ///
+    /// Straight out of the firmware, core 0 applies the relocations for the
+    /// address it was loaded at, moves the bootloader to the end of ARM
+    /// memory, see `relocate`, and enters the copy through the re-entry path.
+    /// That leaves everything below it free for the kernel, whatever the size
+    /// of the bootloader.
+    ///
+    /// # Re-entry
+    ///
+    /// A loaded kernel can hand control back to raspbootin to receive the next
+    /// build without a power cycle. The relocated bootloader is still sitting
+    /// at the end of memory, so the kernel branches to the address in the
+    /// re-entry tag of the boot information with
+    ///
+    /// - `x0` holding `REENTRY_MAGIC`,
+    /// - `x1` holding the address of the device tree to hand to the next
+    ///   kernel, or 0,
+    /// - the MMU and data cache turned off,
+    /// - interrupts masked.
+    ///
+    /// Any core may do this, only core 0 comes back into the bootloader. We go
+    /// through the normal init path, which resets the UART and drains the
+    /// mailbox before the handshake starts over.
+    ///
+    /// # Secondary cores
+    ///
+    /// Cores 1-3 wait for core 0 to relocate the image, then park in the
+    /// relocated copy on a spin table, see `secondary_start`.
+    ///
+    /// # Safety
+    ///
+    /// - Linker script must ensure to place this function at
+    ///   `layout::LOAD_ADDR`.
+    fn _start(x0: u64, x1: u64) -> !;
+}
+
+fn core_id() -> usize {
+    (MPIDR_EL1.get() & CORE_MASK) as usize
+}
+
+/// Whether we run at EL2, where the firmware starts us unless config.txt says
+/// otherwise. Otherwise it is EL1.
+fn at_el2() -> bool {
+    CurrentEL.read(CurrentEL::EL) == CurrentEL::EL::EL2.value
+}
+
+/// Initial stack pointer of each core
+fn stack_top(core: usize) -> u64 {
+    unsafe { read_volatile(&STACK_TOPS[core]) }
+}
+
+/// Start and end of the image we are running from
+fn image() -> (u64, u64) {
+    unsafe { (&__code as *const u64 as u64, &__end as *const u64 as u64) }
+}
+
+/// Start and end of the memory the bootloader occupies, stack included
+pub fn bootloader_memory() -> (u64, u64) {
+    (stack_top(0) - STACK_SIZE, image().1)
+}
+
+/// Start and end of the peripherals, including the ARM local ones
+pub const PERIPHERAL_MEMORY: (u64, u64) = layout::PERIPHERALS;
+
+/// Where a kernel jumps to hand control back to us, see `_start` for the
+/// re-entry ABI.
+pub fn reentry_addr() -> u64 {
+    _start as *const () as u64
+}
+
+/// Value a loaded kernel puts in `x0` when it jumps back to `reentry_addr`
+/// ("RBIN64RE" in ASCII). `start.S` has its own copy.
+pub const REENTRY_MAGIC: u64 = 0x5242_494E_3634_5245;
+
+/// Where `_start` sends cores 1-3, on their own stack in the image the
+/// firmware loaded or in the relocated copy.
+#[no_mangle]
+unsafe extern "C" fn secondary_start(core: u64) -> ! {
+    // Start over in the relocated copy, which sets up a stack there
+    let base = spin_table::wait_for_relocation();
+    if base != image().0 {
+        let start = reentry_addr() - image().0 + base;
+        let start: unsafe extern "C" fn(u64, u64) -> ! = core::mem::transmute(start as *const ());
+        start(0, 0)
+    }
+
+    spin_table::park(core)
+}
+
+/// Copy the image to the end of ARM memory, with the stack right below it,
+/// and relocate the copy. Returns the address of `_start` in the copy.
+/// Called by `_start` on core 0, once the image we are running from agrees
+/// with the address it was loaded at.
 ///
-/// # Safety
+/// The firmware tends to put the device tree at the end of ARM memory as well.
+/// If `device_tree` points at one above us, the copy goes below it instead.
 ///
-/// - Linker script must ensure to place this function at `0x80_000`.
+/// If the firmware does not tell us where memory ends, or there is no room
+/// above us, we stay where we are and return our own `_start`. That is right
+/// where the kernel goes by default, so `loader::fits` turns uploads down
+/// until the host moves them elsewhere with `Command::LoadAddress`.
 #[no_mangle]
-pub unsafe extern "C" fn _start() -> ! {
-    extern "C" {
-        static mut __code: u64;
-        static mut __end: u64;
+unsafe extern "C" fn relocate(device_tree: u64) -> u64 {
+    let (code, end) = image();
+
+    let mut mbox = mbox::Mbox::new();
+    let top = match mbox.get_arm_memory() {
+        Ok((start, size)) => u64::from(start) + u64::from(size),
+        Err(_) => end,
+    };
+    let top = match Fdt::from_addr(device_tree as usize) {
+        Some(fdt) if fdt.addr() as u64 >= end && (fdt.addr() as u64) < top => fdt.addr() as u64,
+        _ => top,
+    };
+
+    let base = top.saturating_sub(end - code) & !(RELOCATION_ALIGN - 1);
+    let base = if base >= end + STACK_SIZE { base } else { code };
+
+    let delta = base - code;
+
+    // The stack goes right below the image, wherever it ends up
+    let shift = base.wrapping_sub(stack_top(0));
+    for top in STACK_TOPS.iter_mut() {
+        write_volatile(top, read_volatile(top).wrapping_add(shift));
     }
-    use crate::runtime_init;
-
-    const CORE_0: u64 = 0;
-    const CORE_MASK: u64 = 0x3;
-
-    if CORE_0 == MPIDR_EL1.get() & CORE_MASK {
-        SP.set(STACK_START);
-
-        compiler_fence(Ordering::SeqCst);
-
-        // This is a hack to not drop back into assembly. Without this get, the compiler fence was not properly
-        // preventing stack allocations prior to the stack being set up.
-        if SP.get() != 0 {
-            rebase_image(&mut __code, &mut __end, RASP_KERN_START as *mut u64);
-
-            // This is a very hacky solution to not having the raw labels available like in assembly and is purely for the
-            // idea that it is "pure Rust". Essentially due to the linker script thinking we are 0x7F_000 and being PIC code
-            // if we force an entry into the GOT (via this as syntax) we will get the correct address for the jump, but in order
-            // to make the compiler think it is dynamic so it doesn't optimize the load/transmute into a relative jump (bad) we
-            // have to build this odd if/else so the compiler can't solve it. Essentially we are trading 3 ASM instructions here
-            // (sub/cmp/csel) for the ability to write this in Rust.
-            // If we don't do this and instead have a relative jump, we run into issues where we are writing the kernel over
-            // ourselves as we execute which is not good...at all.
-            let mut init = runtime_init::init as *mut u8 as u64;
-            if init > RASP_KERN_START {
-                init -= 0x1000;
-            }
-            let init: unsafe fn() -> ! = core::mem::transmute(init as *const ());
-            init()
-        }
 
-        loop {
-            asm::wfe();
-        }
-    } else {
-        // if not core0, infinitely wait for events
-        loop {
-            asm::wfe();
+    if delta != 0 {
+        // No memcpy, the MMU is off and it may well use unaligned accesses
+        for offset in (0..end - code).step_by(8) {
+            write_volatile(
+                (base + offset) as *mut u64,
+                read_volatile((code + offset) as *const u64),
+            );
         }
-    }
-}
 
-pub unsafe extern "C" fn rebase_image<T>(mut scode: *mut T, mut ecode: *mut T, mut oldBase: *mut T)
-where
-    T: Copy,
-{
-    // This is a simple memcpy of the data from one place to another.
-    while scode < ecode {
-        core::ptr::write_volatile(scode, core::ptr::read_volatile(oldBase));
-        scode = scode.offset(1);
-        oldBase = oldBase.offset(1);
+        relocation::apply(base);
     }
+
+    spin_table::relocated(base, delta);
+
+    reentry_addr() - code + base
 }
 
 ////////////////////////////////////////////////////////////////////////////////
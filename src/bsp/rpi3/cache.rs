@@ -0,0 +1,56 @@
+//! Cache and TLB maintenance.
+//!
+//! While the MMU is off every access goes straight to memory, but the kernel
+//! is free to turn the caches on the moment it starts. Everything we wrote
+//! for it has to be cleaned to the point of coherency, and nothing stale may
+//! be left in the instruction cache or the TLBs, before we jump.
+
+use cortex_a::barrier;
+
+global_asm!(include_str!("cache.S"));
+
+extern "C" {
+    fn __dcache_clean_range(start: usize, end: usize);
+    fn __dcache_clean_invalidate_range(start: usize, end: usize);
+    fn __dcache_clean_invalidate_all();
+    fn __icache_invalidate_all();
+    fn __tlb_invalidate_all();
+}
+
+/// Write back `len` bytes at `start` to memory
+pub fn clean_range(start: usize, len: usize) {
+    if len != 0 {
+        unsafe { __dcache_clean_range(start, start + len) }
+    }
+}
+
+/// Write back `len` bytes at `start` to memory and drop them from the data
+/// cache
+pub fn clean_invalidate_range(start: usize, len: usize) {
+    if len != 0 {
+        unsafe { __dcache_clean_invalidate_range(start, start + len) }
+    }
+}
+
+/// Write back and drop the whole data cache, by set/way. Only meaningful
+/// while no other core has its caches on.
+pub fn clean_invalidate_all() {
+    unsafe { __dcache_clean_invalidate_all() }
+}
+
+pub fn invalidate_icache() {
+    unsafe { __icache_invalidate_all() }
+}
+
+/// Drop every translation of the exception level we are running at
+pub fn invalidate_tlbs() {
+    unsafe { __tlb_invalidate_all() }
+}
+
+/// Wait for all outstanding memory accesses and cache maintenance to finish
+pub fn barrier() {
+    unsafe {
+        barrier::dsb(barrier::SY);
+        barrier::isb(barrier::SY);
+    }
+}
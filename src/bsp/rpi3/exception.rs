@@ -0,0 +1,140 @@
+//! Exception handling. Apart from IRQs, nothing in the bootloader expects to
+//! take an exception, so all of them are fatal: the state of the core is
+//! dumped over the UART and we wait for the host to reset the board.
+//!
+//! The only IRQ we enable is the UART's, a GPU interrupt that the BCM2835
+//! controller passes to the BCM2836 local controller, which sends it to us.
+
+use super::intc::Intc;
+use super::local_intc::LocalIntc;
+use super::{at_el2, core_id, Uart};
+use crate::protocol;
+use core::fmt::{self, Write};
+use cortex_a::regs::*;
+
+global_asm!(include_str!("exception.S"));
+
+/// The registers `exception.S` saves
+#[repr(C)]
+pub struct Context {
+    gpr: [u64; 31],
+    __reserved: u64,
+    esr: u64,
+    elr: u64,
+    far: u64,
+    spsr: u64,
+}
+
+const KINDS: [&str; 4] = ["Synchronous", "IRQ", "FIQ", "SError"];
+const ORIGINS: [&str; 4] = [
+    "current EL with SP0",
+    "current EL with SPx",
+    "lower EL using AArch64",
+    "lower EL using AArch32",
+];
+
+/// The exception class from ESR, in words
+fn exception_class(esr: u64) -> &'static str {
+    match (esr >> 26) & 0x3F {
+        0x00 => "Unknown reason",
+        0x01 => "Trapped WFI or WFE",
+        0x07 => "Access to SIMD or floating point",
+        0x0E => "Illegal execution state",
+        0x15 => "SVC from AArch64",
+        0x16 => "HVC from AArch64",
+        0x17 => "SMC from AArch64",
+        0x18 => "Trapped MSR, MRS or system instruction",
+        0x20 => "Instruction abort from a lower EL",
+        0x21 => "Instruction abort from the current EL",
+        0x22 => "PC alignment fault",
+        0x24 => "Data abort from a lower EL",
+        0x25 => "Data abort from the current EL",
+        0x26 => "SP alignment fault",
+        0x2C => "Floating point exception",
+        0x2F => "SError interrupt",
+        0x30 | 0x31 => "Breakpoint",
+        0x32 | 0x33 => "Software step",
+        0x34 | 0x35 => "Watchpoint",
+        0x3C => "BRK instruction",
+        _ => "Reserved",
+    }
+}
+
+// Route physical IRQs to EL2. Without it they are not taken at EL2 at all.
+const HCR_EL2_IMO: u64 = 1 << 4;
+
+extern "C" {
+    fn __install_vectors();
+    fn __irq_unmask();
+    fn __irq_mask();
+}
+
+/// Start taking IRQs at the current exception level, with GPU interrupts
+/// routed to this core
+pub fn unmask_irqs() {
+    LocalIntc::new().route_gpu_irq(core_id());
+
+    if at_el2() {
+        HCR_EL2.set(HCR_EL2.get() | HCR_EL2_IMO);
+    }
+    unsafe { __irq_unmask() };
+}
+
+/// Stop taking IRQs
+pub fn mask_irqs() {
+    unsafe { __irq_mask() };
+    if at_el2() {
+        HCR_EL2.set(HCR_EL2.get() & !HCR_EL2_IMO);
+    }
+}
+
+/// Point VBAR of the current exception level at our vector table
+pub fn install() {
+    unsafe { __install_vectors() };
+}
+
+fn dump(uart: &mut Uart, context: &Context, kind: u64) -> fmt::Result {
+    writeln!(
+        uart,
+        "\n{} exception from the {}",
+        KINDS[(kind % 4) as usize],
+        ORIGINS[(kind / 4) as usize]
+    )?;
+    let esr = context.esr;
+    writeln!(uart, "ESR:  {:#018x} {}", esr, exception_class(esr))?;
+    writeln!(uart, "ELR:  {:#018x}", context.elr)?;
+    writeln!(uart, "FAR:  {:#018x}", context.far)?;
+    writeln!(uart, "SPSR: {:#018x}", context.spsr)?;
+
+    for (i, chunk) in context.gpr.chunks(3).enumerate() {
+        for (j, value) in chunk.iter().enumerate() {
+            write!(uart, "x{:<2}: {:#018x}  ", i * 3 + j, value)?;
+        }
+        writeln!(uart)?;
+    }
+
+    Ok(())
+}
+
+#[no_mangle]
+extern "C" fn irq_handler() {
+    if !LocalIntc::new().gpu_pending(core_id()) {
+        return;
+    }
+
+    if Intc::new().is_pending(Uart::IRQ) {
+        Uart::new().handle_interrupt();
+    }
+}
+
+#[no_mangle]
+extern "C" fn exception_handler(context: &Context, kind: u64) -> ! {
+    let mut uart = Uart::new();
+
+    // Nothing is going to run the interrupt handler anymore
+    uart.set_interrupts(false);
+
+    dump(&mut uart, context, kind).ok();
+
+    protocol::wait_for_reset(&uart)
+}
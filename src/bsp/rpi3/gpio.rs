@@ -5,6 +5,26 @@ register_bitfields! {
     u32,
 
     GPFSEL1 [
+        // Pin 17
+        FSEL17 OFFSET(21) NUMBITS(3) [
+            Input = 0b000,
+            Output = 0b001,
+            // ALT3
+            RTS0 = 0b111,
+            // ALT5
+            RTS1 = 0b010
+        ],
+
+        // Pin 16
+        FSEL16 OFFSET(18) NUMBITS(3) [
+            Input = 0b000,
+            Output = 0b001,
+            // ALT3
+            CTS0 = 0b111,
+            // ALT5
+            CTS1 = 0b010
+        ],
+
         // Pin 15
         FSEL15 OFFSET(15) NUMBITS(3) [
             Input = 0b000,
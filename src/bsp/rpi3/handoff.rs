@@ -0,0 +1,134 @@
+//! Jumping into the loaded kernel.
+//!
+//! Core 0 enters the kernel with
+//!
+//! - `x0` holding the address of the device tree if the firmware passed us
+//!   one, and the address of the `boot_info` structure otherwise,
+//! - `x1` holding the address of the `boot_info` structure.
+//!
+//! That way a kernel that follows the Linux boot protocol finds its device
+//! tree where it expects it. Linux wants `x1` to be zero and complains about
+//! it, but boots all the same. The secondary cores get zero in both.
+
+use super::{at_el2, cache, core_id, spin_table::CORES, stack_top};
+use cortex_a::{asm, regs::*};
+
+global_asm!(include_str!("handoff.S"));
+
+extern "C" {
+    fn __el1_reset();
+}
+
+/// The exception level the loaded kernel is entered at
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u32)]
+pub enum ExceptionLevel {
+    EL1 = 1,
+    EL2 = 2,
+}
+
+// This is a hack because we are on no_std
+impl ExceptionLevel {
+    pub fn from(value: u32) -> Option<ExceptionLevel> {
+        match value {
+            1 => Some(ExceptionLevel::EL1),
+            2 => Some(ExceptionLevel::EL2),
+            _ => None,
+        }
+    }
+}
+
+/// Handing over at EL2 leaves the kernel in the same state the firmware left
+/// us in. Build with `handoff_el1` to drop to EL1 by default instead, the host
+/// can still pick either one for every upload.
+#[cfg(not(feature = "handoff_el1"))]
+pub const DEFAULT_HANDOFF: ExceptionLevel = ExceptionLevel::EL2;
+#[cfg(feature = "handoff_el1")]
+pub const DEFAULT_HANDOFF: ExceptionLevel = ExceptionLevel::EL1;
+
+/// The level the kernel actually ends up at when asking for `level`. We can
+/// only ever go down, if the firmware started us at EL1 that is where we stay.
+pub fn handoff_level(level: ExceptionLevel) -> ExceptionLevel {
+    if at_el2() {
+        level
+    } else {
+        ExceptionLevel::EL1
+    }
+}
+
+// Where el1_entry picks up the kernel, eret can not pass any arguments along
+static mut KERNEL_ENTRY: [usize; CORES] = [0; CORES];
+static mut KERNEL_ARGS: [(usize, usize); CORES] = [(0, 0); CORES];
+
+// The level core 0 handed over at, the secondary cores follow it
+static mut SECONDARY_LEVEL: ExceptionLevel = DEFAULT_HANDOFF;
+
+/// The exception level the secondary cores enter the kernel at
+pub fn secondary_level() -> ExceptionLevel {
+    unsafe { SECONDARY_LEVEL }
+}
+
+/// Jump to the kernel at `entry` with `x0` and `x1` in those registers, at
+/// `level` if possible.
+///
+/// # Safety
+///
+/// - `entry` must point at a kernel that has been fully written to memory,
+///   and cleaned from the data cache.
+pub unsafe fn jump_to_kernel(entry: usize, x0: usize, x1: usize, level: ExceptionLevel) -> ! {
+    let core = core_id();
+    if core == 0 {
+        SECONDARY_LEVEL = level;
+    }
+
+    // Nothing we fetched or translated so far may outlive the handoff
+    cache::invalidate_icache();
+    cache::invalidate_tlbs();
+
+    // Already where we need to be
+    if !at_el2() || level == ExceptionLevel::EL2 {
+        let kernel: extern "C" fn(usize, usize) -> ! = core::mem::transmute(entry as *const ());
+        kernel(x0, x1)
+    }
+
+    KERNEL_ENTRY[core] = entry;
+    KERNEL_ARGS[core] = (x0, x1);
+
+    // Give EL1 access to the physical counter and timer, with no offset on the
+    // virtual one
+    CNTHCTL_EL2.write(CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET);
+    CNTVOFF_EL2.set(0);
+
+    // EL1 runs AArch64, with nothing trapped to EL2
+    HCR_EL2.write(HCR_EL2::RW::EL1IsAarch64);
+
+    // Whatever the firmware left in the EL1 registers, the kernel starts with
+    // the MMU and caches off and FP/SIMD usable
+    __el1_reset();
+
+    // "Return" to EL1h with all interrupts masked, on our stack, which is free
+    // for the kernel to use from now on
+    SPSR_EL2.write(
+        SPSR_EL2::D::Masked
+            + SPSR_EL2::A::Masked
+            + SPSR_EL2::I::Masked
+            + SPSR_EL2::F::Masked
+            + SPSR_EL2::M::EL1h,
+    );
+    ELR_EL2.set(el1_entry as *const () as u64);
+    SP_EL1.set(stack_top(core));
+
+    asm::eret()
+}
+
+/// First thing that runs at EL1
+extern "C" fn el1_entry() -> ! {
+    let core = core_id();
+
+    unsafe {
+        let kernel: extern "C" fn(usize, usize) -> ! =
+            core::mem::transmute(KERNEL_ENTRY[core] as *const ());
+        let (x0, x1) = KERNEL_ARGS[core];
+        kernel(x0, x1)
+    }
+}
@@ -0,0 +1,58 @@
+use super::MMIO_BASE;
+use core::ops;
+use register::mmio::{ReadOnly, WriteOnly};
+
+// BCM2835 interrupt controller, the one the GPU peripherals are wired to.
+//
+// The 64 GPU interrupts are split over two banks of 32. Writing a 1 to an
+// enable or disable register only affects that interrupt, zeros are ignored.
+
+const INTC_BASE: u32 = MMIO_BASE + 0xB200;
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    IRQ_BASIC_PENDING: ReadOnly<u32>,   // 0x00
+    IRQ_PENDING: [ReadOnly<u32>; 2],    // 0x04
+    FIQ_CONTROL: ReadOnly<u32>,         // 0x0C
+    ENABLE_IRQS: [WriteOnly<u32>; 2],   // 0x10
+    ENABLE_BASIC_IRQS: WriteOnly<u32>,  // 0x18
+    DISABLE_IRQS: [WriteOnly<u32>; 2],  // 0x1C
+    DISABLE_BASIC_IRQS: WriteOnly<u32>, // 0x24
+}
+
+pub struct Intc;
+
+impl ops::Deref for Intc {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*Self::ptr() }
+    }
+}
+
+impl Intc {
+    pub fn new() -> Intc {
+        Intc
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr() -> *const RegisterBlock {
+        INTC_BASE as *const _
+    }
+
+    /// Let GPU interrupt `irq` through
+    pub fn enable(&self, irq: usize) {
+        self.ENABLE_IRQS[irq / 32].set(1 << (irq % 32));
+    }
+
+    /// Stop GPU interrupt `irq` from getting through
+    pub fn disable(&self, irq: usize) {
+        self.DISABLE_IRQS[irq / 32].set(1 << (irq % 32));
+    }
+
+    /// Whether GPU interrupt `irq` is enabled and asserted
+    pub fn is_pending(&self, irq: usize) -> bool {
+        self.IRQ_PENDING[irq / 32].get() & (1 << (irq % 32)) != 0
+    }
+}
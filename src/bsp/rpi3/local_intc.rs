@@ -0,0 +1,71 @@
+use super::LOCAL_BASE;
+use core::ops;
+use register::{
+    mmio::{ReadOnly, ReadWrite},
+    register_bitfields,
+};
+
+// BCM2836 ARM local peripherals, which decide which core the interrupts of
+// the BCM2835 controller go to.
+//
+// Descriptions taken from
+// https://www.raspberrypi.org/documentation/hardware/raspberrypi/bcm2836/QA7_rev3.4.pdf
+register_bitfields! {
+    u32,
+
+    /// GPU interrupts routing
+    GPU_INT_ROUTING [
+        /// The core the GPU FIQ goes to
+        FIQ OFFSET(2) NUMBITS(2) [],
+
+        /// The core the GPU IRQ goes to
+        IRQ OFFSET(0) NUMBITS(2) []
+    ],
+
+    /// Core interrupt source
+    CORE_IRQ_SOURCE [
+        /// GPU interrupt, can only be set for the core it is routed to
+        GPU OFFSET(8) NUMBITS(1) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    __reserved_0: [u32; 3],                                         // 0x00
+    GPU_INT_ROUTING: ReadWrite<u32, GPU_INT_ROUTING::Register>,     // 0x0C
+    __reserved_1: [u32; 20],                                        // 0x10
+    CORE_IRQ_SOURCE: [ReadOnly<u32, CORE_IRQ_SOURCE::Register>; 4], // 0x60
+}
+
+pub struct LocalIntc;
+
+impl ops::Deref for LocalIntc {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*Self::ptr() }
+    }
+}
+
+impl LocalIntc {
+    pub fn new() -> LocalIntc {
+        LocalIntc
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr() -> *const RegisterBlock {
+        LOCAL_BASE as *const _
+    }
+
+    /// Send the GPU IRQ to `core`
+    pub fn route_gpu_irq(&self, core: usize) {
+        self.GPU_INT_ROUTING
+            .modify(GPU_INT_ROUTING::IRQ.val(core as u32));
+    }
+
+    /// Whether the IRQ `core` took came from the GPU, see `intc`
+    pub fn gpu_pending(&self, core: usize) -> bool {
+        self.CORE_IRQ_SOURCE[core].is_set(CORE_IRQ_SOURCE::GPU)
+    }
+}
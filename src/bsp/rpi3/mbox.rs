@@ -1,4 +1,5 @@
 use super::MMIO_BASE;
+use core::cmp;
 use core::ops;
 use core::sync::atomic::{compiler_fence, Ordering};
 use cortex_a::asm;
@@ -218,6 +219,9 @@ pub enum Request {
     Request = 0,
 }
 
+// Big enough for the command line, which is the largest thing we ask for
+const BUFFER_LEN: usize = 256;
+
 // Public interface to the mailbox
 #[repr(C)]
 #[repr(align(16))]
@@ -227,7 +231,7 @@ pub struct Mbox {
     // have access to dynamically sized Vec, or Box with no_std
     // currently in this phase of init, so it will have to work
     // for now, if we abstract it aware, the user shouldn't care
-    pub buffer: [u32; 36],
+    pub buffer: [u32; BUFFER_LEN],
 }
 
 /// Deref to RegisterBlock
@@ -250,7 +254,9 @@ impl ops::Deref for Mbox {
 
 impl Mbox {
     pub fn new() -> Mbox {
-        Mbox { buffer: [0; 36] }
+        Mbox {
+            buffer: [0; BUFFER_LEN],
+        }
     }
 
     /// Returns a pointer to the register block
@@ -258,6 +264,14 @@ impl Mbox {
         VIDEOCORE_MBOX as *const _
     }
 
+    /// Throw away any responses still waiting in the mailbox, e.g. ones left
+    /// behind by a kernel that handed control back to us
+    pub fn flush(&self) {
+        while !self.STATUS.is_set(STATUS::EMPTY) {
+            self.READ[0].get();
+        }
+    }
+
     /// Make a mailbox call. Returns Err(MboxError) on failure, Ok(()) success
     pub fn call(&self, channel: Channel) -> Result<()> {
         // wait until we can write to the mailbox
@@ -455,8 +469,40 @@ impl Mbox {
     //TODO Get Clocks
     //pub fn get_clocks(&mut self) -> Result<> {}
 
-    //TODO
-    // pub fn get_command_line(&mut self)
+    /// Copies as much of the command line as fits into `line`, returns the
+    /// number of bytes copied
+    pub fn get_command_line(&mut self, line: &mut [u8]) -> Result<usize> {
+        // Everything but the header, the tag header and the end tag
+        let capacity = (BUFFER_LEN - 6) * 4;
+
+        self.buffer[0] = (BUFFER_LEN * 4) as u32;
+        self.buffer[1] = Request::Request as u32;
+        self.buffer[2] = Tag::GetCommandLine as u32;
+        self.buffer[3] = capacity as u32;
+        self.buffer[4] = 0;
+        for word in self.buffer[5..BUFFER_LEN - 1].iter_mut() {
+            *word = 0;
+        }
+        self.buffer[BUFFER_LEN - 1] = Tag::End as u32;
+
+        compiler_fence(Ordering::Release);
+
+        match self.call(Channel::ArmToVCProperty) {
+            Err(MboxError::ResponseError) => Err(MboxError::ResponseError),
+            Err(MboxError::UnknownError) => Err(MboxError::UnknownError),
+            Ok(()) => {
+                // The top bit only flags this as a response
+                let len = (self.buffer[4] & 0x7FFF_FFFF) as usize;
+                let len = cmp::min(cmp::min(len, capacity), line.len());
+
+                for (i, c) in line[..len].iter_mut().enumerate() {
+                    *c = (self.buffer[5 + i / 4] >> ((i % 4) * 8)) as u8;
+                }
+
+                Ok(len)
+            }
+        }
+    }
 
     pub fn get_dma_channels(&mut self) -> Result<u32> {
         self.buffer[0] = 7 * 4;
@@ -0,0 +1,97 @@
+//! Identity mapped translation tables.
+//!
+//! With the MMU off every access is Device-nGnRnE: slow, uncached, and
+//! unaligned accesses fault. While we receive and check an image we run with
+//! the first 4 GiB identity mapped instead, RAM as normal write-back memory
+//! and the peripherals as device memory, and turn it all off again before the
+//! kernel gets control.
+//!
+//! The VideoCore does not snoop our caches, so the mailbox must only be used
+//! while the MMU is off.
+
+use super::{at_el2, cache, MMIO_BASE};
+
+global_asm!(include_str!("mmu.S"));
+
+extern "C" {
+    fn __mmu_enable(table: u64, mair: u64, tcr: u64);
+    fn __mmu_disable();
+}
+
+// Stage 1 descriptor bits, 4 KiB granule
+const DESCRIPTOR_VALID: u64 = 1;
+const DESCRIPTOR_TABLE: u64 = 1 << 1;
+const DESCRIPTOR_ATTR_NORMAL: u64 = (MAIR_NORMAL_INDEX as u64) << 2;
+const DESCRIPTOR_ATTR_DEVICE: u64 = (MAIR_DEVICE_INDEX as u64) << 2;
+// AP[1], which only has one meaning at EL1 and is RES1 at EL2
+const DESCRIPTOR_AP_EL2_RES1: u64 = 1 << 6;
+const DESCRIPTOR_INNER_SHAREABLE: u64 = 0b11 << 8;
+const DESCRIPTOR_ACCESS_FLAG: u64 = 1 << 10;
+const DESCRIPTOR_EXECUTE_NEVER: u64 = 1 << 54;
+
+const NORMAL_BLOCK: u64 =
+    DESCRIPTOR_VALID | DESCRIPTOR_ATTR_NORMAL | DESCRIPTOR_INNER_SHAREABLE | DESCRIPTOR_ACCESS_FLAG;
+const DEVICE_BLOCK: u64 =
+    DESCRIPTOR_VALID | DESCRIPTOR_ATTR_DEVICE | DESCRIPTOR_ACCESS_FLAG | DESCRIPTOR_EXECUTE_NEVER;
+
+// Normal memory, inner and outer write-back, read and write allocate. The
+// device attribute is all zeroes, Device-nGnRnE.
+const MAIR_NORMAL_INDEX: usize = 0;
+const MAIR_DEVICE_INDEX: usize = 1;
+const MAIR: u64 = 0xFF << (MAIR_NORMAL_INDEX * 8);
+
+// 4 GiB of address space starting at level 1, 4 KiB granule, table walks
+// write-back cacheable and inner shareable
+const TCR_T0SZ: u64 = 32;
+const TCR_WALK: u64 = 0b01 << 8 | 0b01 << 10 | 0b11 << 12;
+// TTBR1_EL1 walks disabled
+const TCR_EL1_EPD1: u64 = 1 << 23;
+// Bits of TCR_EL2 that read as one
+const TCR_EL2_RES1: u64 = 1 << 31 | 1 << 23;
+
+const BLOCK_SIZE_L1: u64 = 1 << 30;
+const BLOCK_SIZE_L2: u64 = 1 << 21;
+
+#[repr(C, align(4096))]
+struct Table([u64; 512]);
+
+static mut LEVEL1: Table = Table([0; 512]);
+static mut LEVEL2: Table = Table([0; 512]);
+
+/// Identity map the first 4 GiB and turn on the MMU and caches
+pub fn enable() {
+    let (res1, tcr) = if at_el2() {
+        (DESCRIPTOR_AP_EL2_RES1, TCR_EL2_RES1 | TCR_WALK | TCR_T0SZ)
+    } else {
+        (0, TCR_EL1_EPD1 | TCR_WALK | TCR_T0SZ)
+    };
+
+    unsafe {
+        // The first GiB holds RAM and, at the top, the peripherals
+        for (i, entry) in LEVEL2.0.iter_mut().enumerate() {
+            let addr = i as u64 * BLOCK_SIZE_L2;
+            *entry = if addr < u64::from(MMIO_BASE) {
+                addr | NORMAL_BLOCK | res1
+            } else {
+                addr | DEVICE_BLOCK | res1
+            };
+        }
+
+        // The second starts with the local peripherals, nothing lives above.
+        // The table descriptor ignores AP[1], it is set for consistency.
+        for entry in LEVEL1.0.iter_mut() {
+            *entry = 0;
+        }
+        LEVEL1.0[0] = &LEVEL2 as *const Table as u64 | DESCRIPTOR_VALID | DESCRIPTOR_TABLE | res1;
+        LEVEL1.0[1] = BLOCK_SIZE_L1 | DEVICE_BLOCK | res1;
+
+        // The tables were written with the caches off, nothing to clean
+        cache::barrier();
+        __mmu_enable(&LEVEL1 as *const Table as u64, MAIR, tcr);
+    }
+}
+
+/// Write the caches back to memory and turn the MMU and caches off again
+pub fn disable() {
+    unsafe { __mmu_disable() }
+}
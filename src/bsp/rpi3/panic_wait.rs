@@ -1,11 +1,37 @@
-//! A panic handler that infinitely waits.
-
-use core::panic::PanicInfo;
-use cortex_a::asm;
-
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    loop {
-        asm::wfe();
-    }
-}
+//! A panic handler that prints the panic over the UART, then waits for the
+//! host to reset the board.
+
+use super::{mbox::Mbox, Uart, Watchdog};
+use crate::protocol;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+
+// How long the host gets to send a reset before the watchdog does it anyway,
+// as long as the watchdog can wait
+const RESET_TIMEOUT_SECS: u32 = Watchdog::MAX_TIMEOUT_SECS;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut uart = Uart::new();
+
+    // This may be the interrupt handler panicking, or IRQs may be masked
+    uart.set_interrupts(false);
+
+    // We may not have gotten as far as setting it up
+    if !uart.is_enabled() {
+        uart.init(&mut Mbox::new(), crate::UART_CLOCK).ok();
+    }
+
+    match info.message() {
+        Some(message) => write!(uart, "\nPanic: {}", message).ok(),
+        None => write!(uart, "\nPanic").ok(),
+    };
+    if let Some(location) = info.location() {
+        write!(uart, " at {}:{}", location.file(), location.line()).ok();
+    }
+    writeln!(uart).ok();
+
+    Watchdog::new().start(RESET_TIMEOUT_SECS);
+
+    protocol::wait_for_reset(&uart)
+}
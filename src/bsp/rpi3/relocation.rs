@@ -0,0 +1,29 @@
+//! Self-relocation.
+//!
+//! The bootloader is linked as a position independent executable, so every
+//! absolute address in it comes with an `R_AARCH64_RELATIVE` entry in
+//! `.rela.dyn`. Applying those for wherever a copy of the image sits lets it
+//! run from there.
+
+use crate::layout;
+
+global_asm!(include_str!("relocation.S"));
+
+// Where the image was linked, for `__apply_relocations`
+#[no_mangle]
+static LINK_ADDR: u64 = layout::LINK_ADDR;
+
+extern "C" {
+    fn __apply_relocations(base: u64);
+}
+
+/// Make every absolute address in the copy of the image at `base` point into
+/// that copy.
+///
+/// # Safety
+///
+/// - There has to be a complete copy of the image at `base`, which may be the
+///   one we are running from.
+pub unsafe fn apply(base: u64) {
+    __apply_relocations(base)
+}
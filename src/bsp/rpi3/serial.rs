@@ -0,0 +1,292 @@
+//! What the PL011 and mini UART drivers have in common: the receive errors
+//! they report, the ways of waiting for a character on top of their
+//! `try_getc`, and XON/XOFF flow control.
+
+use super::Timer;
+use crate::ring_buffer;
+use core::ptr::{read_volatile, write_volatile};
+use core::time::Duration;
+use cortex_a::asm;
+
+/// Why `try_getc` did not return a clean character. Where there is one, the
+/// character as received comes along. The mini UART has no parity and does not
+/// report framing errors or breaks, all it can say is that characters were
+/// lost.
+#[cfg_attr(feature = "mini_uart", allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RxError {
+    /// Nothing was received, or nothing in time for `getc_timeout`
+    Empty,
+    /// The line was held low for longer than a whole character
+    Break,
+    /// The character did not end in a valid stop bit
+    Framing(u8),
+    /// The character's parity bit was wrong
+    Parity(u8),
+    /// The FIFO was full and characters got lost right before this one,
+    /// which is intact
+    Overrun(u8),
+}
+
+impl RxError {
+    pub fn data(self) -> Option<u8> {
+        match self {
+            RxError::Empty | RxError::Break => None,
+            RxError::Framing(c) | RxError::Parity(c) | RxError::Overrun(c) => Some(c),
+        }
+    }
+}
+
+/// Receive errors the UART flagged, see `Uart::line_errors`
+#[derive(Clone, Copy, Default)]
+pub struct LineErrors {
+    pub framing: u32,
+    pub parity: u32,
+    pub breaks: u32,
+    pub overruns: u32,
+}
+
+impl LineErrors {
+    pub const fn new() -> LineErrors {
+        LineErrors {
+            framing: 0,
+            parity: 0,
+            breaks: 0,
+            overruns: 0,
+        }
+    }
+
+    /// The errors that happened after `earlier` was taken
+    pub fn since(&self, earlier: &LineErrors) -> LineErrors {
+        LineErrors {
+            framing: self.framing.wrapping_sub(earlier.framing),
+            parity: self.parity.wrapping_sub(earlier.parity),
+            breaks: self.breaks.wrapping_sub(earlier.breaks),
+            overruns: self.overruns.wrapping_sub(earlier.overruns),
+        }
+    }
+}
+
+/// Receiving characters, all a driver has to provide is `try_getc`
+pub trait Receive {
+    /// Receive a character if there is one, without waiting. Fails with
+    /// `RxError::Empty` if there is not, or with whatever error the UART
+    /// flagged along with it. Every line error is also counted, see
+    /// `Uart::line_errors`.
+    fn try_getc(&self) -> Result<u8, RxError>;
+
+    /// Wait for a character and receive it, whatever state it arrived in.
+    /// Errors are still counted, see `Uart::line_errors`.
+    #[inline(never)]
+    fn getc(&self) -> u8 {
+        loop {
+            match self.try_getc() {
+                Ok(c) => return c,
+                Err(RxError::Empty) => asm::nop(),
+                Err(e) => return e.data().unwrap_or(0),
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for a character, see `try_getc`
+    fn getc_timeout(&self, timeout: Duration) -> Result<u8, RxError> {
+        let timer = Timer::new();
+        let start = timer.ticks();
+        let ticks = timer.duration_to_ticks(timeout);
+
+        loop {
+            match self.try_getc() {
+                Err(RxError::Empty) if timer.ticks() - start <= ticks => asm::nop(),
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Build with `uart_software_flow_control` to start with XON/XOFF on, the host
+/// can still switch it either way with `Command::SoftwareFlowControl`.
+pub const DEFAULT_SOFTWARE_FLOW_CONTROL: bool = cfg!(feature = "uart_software_flow_control");
+
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+
+// We send XOFF once the receive buffer is this full, and XON once it has
+// drained again
+const THROTTLE_AT: usize = ring_buffer::CAPACITY * 3 / 4;
+const RELEASE_AT: usize = ring_buffer::CAPACITY / 4;
+
+// Characters taken off the receive buffer while looking for XON/XOFF
+const STASH_SIZE: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Flow {
+    enabled: bool,
+    /// Text is being sent, see `SoftwareFlow::software_flow_control`
+    active: bool,
+    /// The host sent XOFF
+    paused: bool,
+    /// We sent XOFF
+    throttled: bool,
+    stash: [u16; STASH_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl Flow {
+    fn push(&mut self, data: u16) {
+        self.stash[(self.head + self.len) % STASH_SIZE] = data;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u16> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let data = self.stash[self.head];
+        self.head = (self.head + 1) % STASH_SIZE;
+        self.len -= 1;
+
+        Some(data)
+    }
+}
+
+// Only one of the drivers is ever built
+static mut FLOW: Flow = Flow {
+    enabled: false,
+    active: false,
+    paused: false,
+    throttled: false,
+    stash: [0; STASH_SIZE],
+    head: 0,
+    len: 0,
+};
+
+/// XON/XOFF flow control, for adapters without RTS/CTS lines, on top of what
+/// a driver provides. It only applies to text, see `software_flow_control`,
+/// the handshake, its replies and uploads are binary and may contain XON and
+/// XOFF themselves.
+pub trait SoftwareFlow {
+    /// Send a character, whatever the flow control says
+    fn put(&self, c: u8);
+
+    /// The next character received as the driver stores it, error flags and
+    /// all, from its receive buffer first
+    fn get(&self) -> Option<u16>;
+
+    /// How many characters are waiting in the driver's receive buffer
+    fn buffered(&self) -> usize;
+
+    /// Turn XON/XOFF flow control on or off
+    fn set_software_flow_control(&self, enabled: bool) {
+        let mut flow = unsafe { read_volatile(&FLOW) };
+        flow.enabled = enabled;
+        unsafe { write_volatile(&mut FLOW, flow) };
+
+        if !enabled {
+            self.throttle_host();
+        }
+    }
+
+    /// Run `f`, which sends text, with XON/XOFF flow control in effect if it
+    /// is on. XON and XOFF from the host never show up in `getc` meanwhile,
+    /// `send` holds off between them, and we send XOFF ourselves when the
+    /// receive buffer fills up faster than it is read.
+    fn software_flow_control<F: FnOnce()>(&self, f: F) {
+        let mut flow = unsafe { read_volatile(&FLOW) };
+        flow.active = flow.enabled;
+        flow.paused = false;
+        unsafe { write_volatile(&mut FLOW, flow) };
+
+        f();
+
+        let mut flow = unsafe { read_volatile(&FLOW) };
+        flow.active = false;
+        flow.paused = false;
+        unsafe { write_volatile(&mut FLOW, flow) };
+    }
+
+    /// Send a character
+    fn send(&self, c: char) {
+        if unsafe { read_volatile(&FLOW.active) } {
+            // Once the stash is full the host's XON may be stuck behind it,
+            // so go ahead rather than wait forever
+            loop {
+                self.poll_host();
+
+                let flow = unsafe { read_volatile(&FLOW) };
+                if !flow.paused || flow.len == STASH_SIZE {
+                    break;
+                }
+
+                asm::nop();
+            }
+        }
+
+        self.put(c as u8);
+    }
+
+    /// The next character as `get` has it, stashed ones first
+    fn receive(&self) -> Option<u16> {
+        if unsafe { read_volatile(&FLOW.active) } {
+            self.poll_host();
+        } else {
+            self.throttle_host();
+        }
+
+        let mut flow = unsafe { read_volatile(&FLOW) };
+        match flow.pop() {
+            Some(data) => {
+                unsafe { write_volatile(&mut FLOW, flow) };
+                Some(data)
+            }
+            None if flow.active => None,
+            None => self.get(),
+        }
+    }
+
+    /// How many characters were put aside while looking for XON/XOFF
+    fn stashed(&self) -> usize {
+        unsafe { read_volatile(&FLOW.len) }
+    }
+
+    /// Move what the host has sent into the stash, acting on XON/XOFF along
+    /// the way
+    fn poll_host(&self) {
+        let mut flow = unsafe { read_volatile(&FLOW) };
+
+        while flow.len < STASH_SIZE {
+            // Anything with an error flag set is just data
+            match self.get() {
+                Some(d) if d == u16::from(XOFF) => flow.paused = true,
+                Some(d) if d == u16::from(XON) => flow.paused = false,
+                Some(d) => flow.push(d),
+                None => break,
+            }
+        }
+
+        unsafe { write_volatile(&mut FLOW, flow) };
+        self.throttle_host();
+    }
+
+    /// Send XOFF while text is sent and the receive buffer is filling up, and
+    /// XON once it has drained or flow control is turned off. XON only ever
+    /// follows our own XOFF.
+    fn throttle_host(&self) {
+        let mut flow = unsafe { read_volatile(&FLOW) };
+        let buffered = self.buffered();
+
+        let reply = if !flow.throttled && flow.active && buffered >= THROTTLE_AT {
+            flow.throttled = true;
+            XOFF
+        } else if flow.throttled && (!flow.enabled || buffered <= RELEASE_AT) {
+            flow.throttled = false;
+            XON
+        } else {
+            return;
+        };
+
+        unsafe { write_volatile(&mut FLOW, flow) };
+        self.put(reply);
+    }
+}
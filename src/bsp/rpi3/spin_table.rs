@@ -0,0 +1,110 @@
+//! Parking of the secondary cores, so an SMP kernel can start them with the
+//! spin-table enable method.
+//!
+//! Every secondary core waits in `park` and polls its own release address.
+//! Once the kernel writes an entry point there and sends `sev`, the core jumps
+//! to it at the same exception level core 0 handed over at. The addresses are
+//! published as `cpu-release-addr` in the device tree.
+//!
+//! The firmware's armstub holds cores 1-3 in a spin table of its own at
+//! 0xd8-0xf0 until someone writes an entry point there, so they never come to
+//! `_start` by themselves. Once the relocated copy is ready core 0 points them
+//! at its `_start`, from where they go on to `park`.
+//!
+//! All of these live in `.data` rather than `.bss`, the secondary cores start
+//! using them while core 0 is still busy zeroing the `.bss`.
+
+use super::handoff;
+use crate::layout;
+use core::ptr::{read_volatile, write_volatile};
+use cortex_a::asm;
+
+global_asm!(include_str!("spin_table.S"));
+
+extern "C" {
+    fn __sev();
+}
+
+pub const CORES: usize = layout::CORES;
+
+// The armstub's spin table, one entry point per core starting with core 0
+const FIRMWARE_RELEASE_ADDRS: u64 = 0xd8;
+
+// Where core 0 moved the image to, 0 until it has
+#[link_section = ".data"]
+static mut RELOCATED: u64 = 0;
+// Where the kernel writes the entry point for each core
+#[link_section = ".data"]
+static mut RELEASE_ADDRS: [u64; CORES] = [0; CORES];
+// Which cores are actually waiting in `park`
+#[link_section = ".data"]
+static mut PARKED: [u64; CORES] = [0; CORES];
+
+/// Called by core 0 from the image the firmware loaded once the relocated
+/// copy at `base`, `delta` bytes above, is ready. Lets the secondary cores
+/// move over to it, whether they are still in the firmware's spin table or
+/// already waiting in `wait_for_relocation`.
+pub unsafe fn relocated(base: u64, delta: u64) {
+    // The copy has to know it is the relocated one as well
+    write_volatile(
+        (&mut RELOCATED as *mut u64 as u64 + delta) as *mut u64,
+        base,
+    );
+    write_volatile(&mut RELOCATED, base);
+
+    let entry = super::reentry_addr() + delta;
+    for core in 1..CORES {
+        write_volatile(
+            (FIRMWARE_RELEASE_ADDRS + 8 * core as u64) as *mut u64,
+            entry,
+        );
+    }
+
+    __sev();
+}
+
+/// Called by the secondary cores, returns where the image has been relocated
+/// to once it is ready.
+pub unsafe fn wait_for_relocation() -> u64 {
+    loop {
+        let base = read_volatile(&RELOCATED);
+        if base != 0 {
+            return base;
+        }
+
+        asm::wfe();
+    }
+}
+
+/// The release address of a core, if that core is parked and waiting for it
+pub fn release_addr(core: usize) -> Option<u64> {
+    unsafe {
+        if core < CORES && read_volatile(&PARKED[core]) != 0 {
+            Some(&RELEASE_ADDRS[core] as *const u64 as u64)
+        } else {
+            None
+        }
+    }
+}
+
+/// Wait for the kernel to release this core.
+///
+/// # Safety
+///
+/// - Must run from the relocated copy, on the core's own stack.
+pub unsafe fn park(core: u64) -> ! {
+    let core = core as usize;
+
+    write_volatile(&mut RELEASE_ADDRS[core], 0);
+    write_volatile(&mut PARKED[core], 1);
+
+    loop {
+        asm::wfe();
+
+        let entry = read_volatile(&RELEASE_ADDRS[core]);
+        if entry != 0 {
+            write_volatile(&mut PARKED[core], 0);
+            handoff::jump_to_kernel(entry as usize, 0, 0, handoff::secondary_level())
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use core::time::Duration;
+use cortex_a::regs::*;
+
+/// The ARM generic timer. The counter runs at a fixed frequency set up by the
+/// firmware and keeps counting across exception levels, which makes it
+/// usable for measurements without any setup.
+pub struct Timer;
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer
+    }
+
+    /// Raw counter value
+    pub fn ticks(&self) -> u64 {
+        CNTPCT_EL0.get()
+    }
+
+    /// Counter frequency in Hz
+    pub fn frequency(&self) -> u64 {
+        u64::from(CNTFRQ_EL0.get())
+    }
+
+    /// Convert a number of counter ticks into microseconds
+    pub fn ticks_to_micros(&self, ticks: u64) -> u64 {
+        // Widen first, the multiplication overflows a u64 after a few days
+        ((u128::from(ticks) * 1_000_000) / u128::from(self.frequency())) as u64
+    }
+
+    /// Convert a duration into counter ticks
+    pub fn duration_to_ticks(&self, duration: Duration) -> u64 {
+        ((duration.as_nanos() * u128::from(self.frequency())) / 1_000_000_000) as u64
+    }
+}
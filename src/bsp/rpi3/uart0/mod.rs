@@ -1,10 +1,14 @@
 use super::gpio;
+use super::intc::Intc;
 use super::mbox::{Clocks, Mbox};
+use super::serial::{LineErrors, Receive, RxError, SoftwareFlow, DEFAULT_SOFTWARE_FLOW_CONTROL};
 use super::MMIO_BASE;
-use core::fmt;
+use crate::ring_buffer::RingBuffer;
 use core::ops;
+use core::ptr::{read_volatile, write_volatile};
+use core::time::Duration;
 use cortex_a::asm;
-use register::{mmio::*, register_bitfields};
+use register::{mmio::*, register_bitfields, LocalRegisterCopy};
 
 // PL011 UART registers.
 //
@@ -13,6 +17,32 @@ use register::{mmio::*, register_bitfields};
 register_bitfields! {
     u32,
 
+    /// Data Register
+    DR [
+        /// Overrun error. This bit is set to 1 if data is received
+        /// and the receive FIFO is already full.
+        OE OFFSET(11) NUMBITS(1) [],
+
+        /// Break error. This bit is set to 1 if a break condition was
+        /// detected, indicating that the received data input was held
+        /// LOW for longer than a full-word transmission time.
+        BE OFFSET(10) NUMBITS(1) [],
+
+        /// Parity error. When set to 1, it indicates that the parity
+        /// of the received data character does not match the parity
+        /// that the EPS and SPS bits in the Line Control Register,
+        /// UART_LCRH select.
+        PE OFFSET(9) NUMBITS(1) [],
+
+        /// Framing error. When set to 1, it indicates that the
+        /// received character did not have a valid stop bit.
+        FE OFFSET(8) NUMBITS(1) [],
+
+        /// Receive (read) data character. Transmit (write) data
+        /// character.
+        DATA OFFSET(0) NUMBITS(8) []
+    ],
+
     /// Flag Register
     FR [
         /// Transmit FIFO full. The meaning of this bit depends on the
@@ -27,7 +57,13 @@ register_bitfields! {
         /// FIFO is disabled, this bit is set when the receive holding
         /// register is empty. If the FIFO is enabled, the RXFE bit is
         /// set when the receive FIFO is empty.
-        RXFE OFFSET(4) NUMBITS(1) []
+        RXFE OFFSET(4) NUMBITS(1) [],
+
+        /// UART busy. If this bit is set to 1, the UART is busy
+        /// transmitting data. This bit remains set until the complete
+        /// byte, including all the stop bits, has been sent from the
+        /// shift register.
+        BUSY OFFSET(3) NUMBITS(1) []
     ],
 
     /// Integer Baud rate divisor
@@ -44,6 +80,10 @@ register_bitfields! {
 
     /// Line Control register
     LCRH [
+        /// Stick parity select. When set along with PEN, the parity
+        /// bit is transmitted and checked as the inverse of EPS.
+        SPS OFFSET(7) NUMBITS(1) [],
+
         /// Word length. These bits indicate the number of data bits
         /// transmitted or received in a frame.
         WLEN OFFSET(5) NUMBITS(2) [
@@ -51,11 +91,43 @@ register_bitfields! {
             SixBit = 0b01,
             SevenBit = 0b10,
             EightBit = 0b11
-        ]
+        ],
+
+        /// Enable FIFOs. If this bit is set to 1, transmit and
+        /// receive FIFO buffers are enabled (FIFO mode). When cleared
+        /// to 0 the FIFOs are disabled (character mode), that is, the
+        /// FIFOs become 1-byte-deep holding registers.
+        FEN OFFSET(4) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// Two stop bits select. If this bit is set to 1, two stop
+        /// bits are transmitted at the end of the frame.
+        STP2 OFFSET(3) NUMBITS(1) [],
+
+        /// Even parity select. When set, even parity is generated and
+        /// checked, odd parity otherwise. Has no effect when PEN is
+        /// cleared.
+        EPS OFFSET(2) NUMBITS(1) [],
+
+        /// Parity enable. If this bit is set to 1, parity checking
+        /// and generation is enabled.
+        PEN OFFSET(1) NUMBITS(1) []
     ],
 
     /// Control Register
     CR [
+        /// CTS hardware flow control enable. If this bit is set to 1,
+        /// data is only transmitted when the nUARTCTS signal is
+        /// asserted.
+        CTSEN  OFFSET(15) NUMBITS(1) [],
+
+        /// RTS hardware flow control enable. If this bit is set to 1,
+        /// data is only requested when there is space in the receive
+        /// FIFO for it to be received.
+        RTSEN  OFFSET(14) NUMBITS(1) [],
+
         /// Receive enable. If this bit is set to 1, the receive
         /// section of the UART is enabled. Data reception occurs for
         /// UART signals. When the UART is disabled in the middle of
@@ -76,6 +148,14 @@ register_bitfields! {
             Enabled = 1
         ],
 
+        /// Loopback enable. If this bit is set to 1, the transmit
+        /// serial output is fed back into the receive serial input
+        /// internally.
+        LBE    OFFSET(7) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
         /// UART enable
         UARTEN OFFSET(0) NUMBITS(1) [
             /// If the UART is disabled in the middle of transmission
@@ -86,8 +166,39 @@ register_bitfields! {
         ]
     ],
 
+    /// Interrupt FIFO Level Select Register
+    IFLS [
+        /// Receive interrupt FIFO level select
+        RXIFLSEL OFFSET(3) NUMBITS(3) [],
+
+        /// Transmit interrupt FIFO level select
+        TXIFLSEL OFFSET(0) NUMBITS(3) []
+    ],
+
+    /// Interrupt Mask Set/Clear Register
+    IMSC [
+        /// Receive timeout interrupt mask
+        RTIM OFFSET(6) NUMBITS(1) [],
+
+        /// Receive interrupt mask
+        RXIM OFFSET(4) NUMBITS(1) []
+    ],
+
+    /// Raw Interrupt Status Register
+    RIS [
+        /// Receive interrupt status, set while the receive FIFO holds at
+        /// least as much as IFLS.RXIFLSEL asks for
+        RXRIS OFFSET(4) NUMBITS(1) []
+    ],
+
     /// Interupt Clear Register
     ICR [
+        /// Receive timeout interrupt clear
+        RTIC OFFSET(6) NUMBITS(1) [],
+
+        /// Receive interrupt clear
+        RXIC OFFSET(4) NUMBITS(1) [],
+
         /// Meta field for all pending interrupts
         ALL OFFSET(0) NUMBITS(11) []
     ]
@@ -95,21 +206,114 @@ register_bitfields! {
 
 const UART_BASE: u32 = MMIO_BASE + 0x20_1000;
 
+/// What the boot info calls this UART
+pub const UART_NUMBER: u32 = 0;
+
+/// The baud rate `init` sets up, assuming a 4 MHz UART clock
+pub const BAUD_RATE: u32 = 115_200;
+
 #[allow(non_snake_case)]
 #[repr(C)]
 pub struct RegisterBlock {
-    DR: ReadWrite<u32>,                   // 0x00
+    DR: ReadWrite<u32, DR::Register>,     // 0x00
     __reserved_0: [u32; 5],               // 0x04
     FR: ReadOnly<u32, FR::Register>,      // 0x18
     __reserved_1: [u32; 2],               // 0x1c
     IBRD: WriteOnly<u32, IBRD::Register>, // 0x24
     FBRD: WriteOnly<u32, FBRD::Register>, // 0x28
-    LCRH: WriteOnly<u32, LCRH::Register>, // 0x2C
-    CR: WriteOnly<u32, CR::Register>,     // 0x30
-    __reserved_2: [u32; 4],               // 0x34
+    LCRH: ReadWrite<u32, LCRH::Register>, // 0x2C
+    CR: ReadWrite<u32, CR::Register>,     // 0x30
+    IFLS: ReadWrite<u32, IFLS::Register>, // 0x34
+    IMSC: ReadWrite<u32, IMSC::Register>, // 0x38
+    RIS: ReadOnly<u32, RIS::Register>,    // 0x3C
+    __reserved_3: u32,                    // 0x40
     ICR: WriteOnly<u32, ICR::Register>,   // 0x44
 }
 
+// Whether the interrupt handler is filling `RX_BUFFER`
+static mut INTERRUPTS: bool = false;
+
+// Characters the interrupt handler received, as read from DR
+static RX_BUFFER: RingBuffer = RingBuffer::new();
+
+// The interrupt handler found `RX_BUFFER` full and lost a character
+static mut DROPPED: bool = false;
+
+// The UART clock `init` asked the firmware for
+static mut CLOCK: u32 = 0;
+
+// Every error `try_getc` has seen since boot
+static mut LINE_ERRORS: LineErrors = LineErrors::new();
+
+// Only 8N1 is used by the loader itself
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum WordLength {
+    Five = 5,
+    Six = 6,
+    Seven = 7,
+    Eight = 8,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// Parity bit always 1
+    Mark,
+    /// Parity bit always 0
+    Space,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// How the line is framed, see `Uart::set_line`
+#[derive(Clone, Copy)]
+pub struct LineConfig {
+    pub word_length: WordLength,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    /// Use the 16 byte FIFOs instead of a single holding register
+    pub fifo: bool,
+}
+
+impl LineConfig {
+    /// 8N1 with the FIFOs on, what `init` sets up
+    pub fn new() -> LineConfig {
+        LineConfig {
+            word_length: WordLength::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            fifo: true,
+        }
+    }
+}
+
+/// FIFO fill level an interrupt is raised at, see `Uart::set_fifo_levels`
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum FifoLevel {
+    OneEighth = 0b000,
+    OneQuarter = 0b001,
+    OneHalf = 0b010,
+    ThreeQuarters = 0b011,
+    SevenEighths = 0b100,
+}
+
+/// Build with `uart_flow_control` to start with RTS/CTS on, the host can still
+/// switch it either way with `Command::FlowControl`.
+pub const DEFAULT_FLOW_CONTROL: bool = cfg!(feature = "uart_flow_control");
+
+// How long `self_test` waits for each character to come back
+const SELF_TEST_TIMEOUT: Duration = Duration::from_millis(10);
+
 pub enum UartError {
     MailboxError,
 }
@@ -126,6 +330,9 @@ impl ops::Deref for Uart {
 }
 
 impl Uart {
+    /// The GPU interrupt the PL011 raises
+    pub const IRQ: usize = 57;
+
     pub fn new() -> Uart {
         Uart
     }
@@ -138,6 +345,7 @@ impl Uart {
         self.CR.set(0);
 
         mbox.set_clock_rate(Clocks::UART, clock_speed, 0);
+        unsafe { write_volatile(&mut CLOCK, clock_speed) };
 
         // map UART0 to GPIO pins
         unsafe {
@@ -161,41 +369,257 @@ impl Uart {
         self.ICR.write(ICR::ALL::CLEAR);
         self.IBRD.write(IBRD::IBRD.val(2)); // Results in 115200 baud
         self.FBRD.write(FBRD::FBRD.val(0xB));
-        self.LCRH.write(LCRH::WLEN::EightBit); // 8N1
+        self.set_line(LineConfig::new());
+        self.set_fifo_levels(FifoLevel::OneHalf, FifoLevel::OneHalf);
         self.CR
             .write(CR::UARTEN::Enabled + CR::TXE::Enabled + CR::RXE::Enabled);
+        self.set_flow_control(DEFAULT_FLOW_CONTROL);
+        self.set_software_flow_control(DEFAULT_SOFTWARE_FLOW_CONTROL);
 
         Ok(())
     }
 
-    /// Send a character
-    pub fn send(&self, c: char) {
-        // wait until we can send
+    /// The clock the baud rate is derived from, in Hz
+    pub fn clock(&self) -> u32 {
+        unsafe { read_volatile(&CLOCK) }
+    }
+
+    /// Change the framing. The UART is disabled while the PL011 takes the new
+    /// configuration, after finishing what it is sending.
+    pub fn set_line(&self, config: LineConfig) {
+        let cr = self.CR.get();
+        self.flush();
+        self.CR.set(0);
+
+        let word_length = match config.word_length {
+            WordLength::Five => LCRH::WLEN::FiveBit,
+            WordLength::Six => LCRH::WLEN::SixBit,
+            WordLength::Seven => LCRH::WLEN::SevenBit,
+            WordLength::Eight => LCRH::WLEN::EightBit,
+        };
+        let parity = match config.parity {
+            Parity::None => LCRH::PEN::CLEAR,
+            Parity::Odd => LCRH::PEN::SET,
+            Parity::Even => LCRH::PEN::SET + LCRH::EPS::SET,
+            Parity::Mark => LCRH::PEN::SET + LCRH::SPS::SET,
+            Parity::Space => LCRH::PEN::SET + LCRH::EPS::SET + LCRH::SPS::SET,
+        };
+        let stop_bits = match config.stop_bits {
+            StopBits::One => LCRH::STP2::CLEAR,
+            StopBits::Two => LCRH::STP2::SET,
+        };
+        let fifo = if config.fifo {
+            LCRH::FEN::Enabled
+        } else {
+            LCRH::FEN::Disabled
+        };
+
+        self.LCRH.write(word_length + parity + stop_bits + fifo);
+        self.CR.set(cr);
+    }
+
+    /// Turn RTS/CTS flow control on or off. GPIO16 and 17 are handed to the
+    /// UART as CTS0 and RTS0 while it is on. RTS is dropped once the receive
+    /// FIFO is filled up to its interrupt level, so the host holds off instead
+    /// of overrunning us, and we stop sending while the host drops CTS.
+    pub fn set_flow_control(&self, enabled: bool) {
+        self.flush();
+
+        unsafe {
+            if enabled {
+                (*gpio::GPFSEL1).modify(gpio::GPFSEL1::FSEL16::CTS0 + gpio::GPFSEL1::FSEL17::RTS0);
+            } else {
+                (*gpio::GPFSEL1)
+                    .modify(gpio::GPFSEL1::FSEL16::Input + gpio::GPFSEL1::FSEL17::Input);
+            }
+        }
+
+        if enabled {
+            self.CR.modify(CR::RTSEN::SET + CR::CTSEN::SET);
+        } else {
+            self.CR.modify(CR::RTSEN::CLEAR + CR::CTSEN::CLEAR);
+        }
+    }
+
+    /// Set the FIFO fill levels the receive and transmit interrupts fire at
+    pub fn set_fifo_levels(&self, rx: FifoLevel, tx: FifoLevel) {
+        self.IFLS
+            .write(IFLS::RXIFLSEL.val(rx as u32) + IFLS::TXIFLSEL.val(tx as u32));
+    }
+
+    /// Send every possible character to ourselves in loopback mode and check
+    /// that each one comes back intact with the current line configuration.
+    /// Anything the host sends in the meantime is lost.
+    pub fn self_test(&self) -> bool {
+        let cr = self.CR.get();
+        self.flush();
+        self.CR.set(0);
+        self.CR
+            .write(CR::UARTEN::Enabled + CR::TXE::Enabled + CR::RXE::Enabled + CR::LBE::Enabled);
+
+        while !self.FR.is_set(FR::RXFE) {
+            self.DR.get();
+        }
+
+        let mask = (1 << (5 + self.LCRH.read(LCRH::WLEN))) - 1;
+        let mut passed = true;
+        for c in 0..=255u8 {
+            if u32::from(c) & mask != u32::from(c) {
+                continue;
+            }
+
+            self.send(c as char);
+
+            if self.getc_timeout(SELF_TEST_TIMEOUT) != Ok(c) {
+                passed = false;
+                break;
+            }
+        }
+
+        self.flush();
+        self.CR.set(0);
+        while !self.FR.is_set(FR::RXFE) {
+            self.DR.get();
+        }
+        self.CR.set(cr);
+
+        passed
+    }
+
+    /// Whether `init` has run
+    pub fn is_enabled(&self) -> bool {
+        self.CR.is_set(CR::UARTEN)
+    }
+
+    /// Wait until everything sent so far has left the shift register
+    pub fn flush(&self) {
         loop {
-            if !self.FR.is_set(FR::TXFF) {
+            if !self.FR.is_set(FR::BUSY) {
                 break;
             }
 
             asm::nop();
         }
+    }
 
-        // write the character to the buffer
-        self.DR.set(c as u32);
+    /// Whether a character is waiting to be received
+    pub fn can_read(&self) -> bool {
+        self.stashed() > 0 || !RX_BUFFER.is_empty() || !self.FR.is_set(FR::RXFE)
     }
 
-    /// Receive a character
-    #[inline(never)]
-    pub fn getc(&self) -> u8 {
-        // wait until something is in the buffer
+    /// Have the receive interrupts move characters into a buffer as soon as
+    /// they arrive, so none are lost while whoever reads them is busy with
+    /// something else. IRQs have to be unmasked as well, see
+    /// `exception::unmask_irqs`.
+    pub fn set_interrupts(&self, enabled: bool) {
+        let intc = Intc::new();
+
+        if enabled {
+            unsafe { write_volatile(&mut INTERRUPTS, true) };
+            self.ICR.write(ICR::RXIC::SET + ICR::RTIC::SET);
+            self.IMSC.write(IMSC::RXIM::SET + IMSC::RTIM::SET);
+            intc.enable(Uart::IRQ);
+        } else {
+            intc.disable(Uart::IRQ);
+            self.IMSC.set(0);
+            unsafe { write_volatile(&mut INTERRUPTS, false) };
+        }
+    }
+
+    /// Move everything in the receive FIFO into the receive buffer, called by
+    /// the IRQ handler
+    pub fn handle_interrupt(&self) {
+        while !self.FR.is_set(FR::RXFE) {
+            let mut data = self.DR.get();
+
+            // Flag the character after a lost one, like the PL011 does when
+            // its FIFO was full
+            if unsafe { read_volatile(&DROPPED) } {
+                data |= DR::OE::SET.value;
+            }
+
+            let dropped = RX_BUFFER.push(data as u16).is_err();
+            unsafe { write_volatile(&mut DROPPED, dropped) };
+        }
+
+        self.ICR.write(ICR::RXIC::SET + ICR::RTIC::SET);
+    }
+
+    /// The receive errors counted by `try_getc` since boot
+    pub fn line_errors(&self) -> LineErrors {
+        unsafe { read_volatile(&LINE_ERRORS) }
+    }
+}
+
+impl SoftwareFlow for Uart {
+    fn put(&self, c: u8) {
+        // wait until we can send
         loop {
-            if !self.FR.is_set(FR::RXFE) {
+            if !self.FR.is_set(FR::TXFF) {
                 break;
             }
 
             asm::nop();
         }
 
-        // read it and return
-        self.DR.get() as u8
+        // write the character to the buffer
+        self.DR.set(u32::from(c));
+    }
+
+    /// As read from DR. While the interrupt handler is filling the receive
+    /// buffer, that is the only place to take it from, or the order gets
+    /// mixed up.
+    fn get(&self) -> Option<u16> {
+        if let Some(data) = RX_BUFFER.pop() {
+            return Some(data);
+        }
+
+        if unsafe { read_volatile(&INTERRUPTS) } || self.FR.is_set(FR::RXFE) {
+            None
+        } else {
+            Some(self.DR.get() as u16)
+        }
+    }
+
+    fn buffered(&self) -> usize {
+        RX_BUFFER.len()
+    }
+}
+
+impl Receive for Uart {
+    fn try_getc(&self) -> core::result::Result<u8, RxError> {
+        let data = match self.receive() {
+            Some(data) => LocalRegisterCopy::<u32, DR::Register>::new(u32::from(data)),
+            None => return Err(RxError::Empty),
+        };
+        let c = data.read(DR::DATA) as u8;
+
+        let mut errors = self.line_errors();
+        if data.is_set(DR::FE) {
+            errors.framing += 1;
+        }
+        if data.is_set(DR::PE) {
+            errors.parity += 1;
+        }
+        if data.is_set(DR::BE) {
+            errors.breaks += 1;
+        }
+        if data.is_set(DR::OE) {
+            errors.overruns += 1;
+        }
+        unsafe { write_volatile(&mut LINE_ERRORS, errors) };
+
+        // A break shows up as a framing error as well
+        if data.is_set(DR::BE) {
+            Err(RxError::Break)
+        } else if data.is_set(DR::FE) {
+            Err(RxError::Framing(c))
+        } else if data.is_set(DR::PE) {
+            Err(RxError::Parity(c))
+        } else if data.is_set(DR::OE) {
+            Err(RxError::Overrun(c))
+        } else {
+            Ok(c)
+        }
     }
 }
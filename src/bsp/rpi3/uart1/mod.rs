@@ -0,0 +1,403 @@
+use super::gpio;
+use super::intc::Intc;
+use super::mbox::{Clocks, Mbox};
+use super::serial::{LineErrors, Receive, RxError, SoftwareFlow, DEFAULT_SOFTWARE_FLOW_CONTROL};
+use super::MMIO_BASE;
+use crate::ring_buffer::RingBuffer;
+use core::ops;
+use core::ptr::{read_volatile, write_volatile};
+use cortex_a::asm;
+use register::{mmio::*, register_bitfields, LocalRegisterCopy};
+
+// Mini UART (UART1) registers, part of the AUX peripheral block.
+//
+// Descriptions taken from
+// https://github.com/raspberrypi/documentation/files/1888662/BCM2837-ARM-Peripherals.-.Revised.-.V2-1.pdf
+register_bitfields! {
+    u32,
+
+    /// Auxiliary enables
+    AUX_ENABLES [
+        /// If set the mini UART is enabled. The UART will immediately
+        /// start receiving data, especially if the UART1_RX line is
+        /// low. If clear the mini UART is disabled. That also disables
+        /// any mini UART register access.
+        MINI_UART_ENABLE OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Mini UART I/O Data
+    AUX_MU_IO [
+        /// Writing places the character in the transmit FIFO, reading
+        /// takes one from the receive FIFO.
+        DATA OFFSET(0) NUMBITS(8) []
+    ],
+
+    /// Mini UART Interrupt Enable
+    AUX_MU_IER [
+        /// If set the interrupt line is asserted whenever the receive
+        /// FIFO holds at least 1 byte. The datasheet has this bit and
+        /// the transmit one swapped.
+        RX_INT OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Mini UART Interrupt Identify
+    AUX_MU_IIR [
+        /// Writing with bit 1 set will clear the receive FIFO. Writing
+        /// with bit 2 set will clear the transmit FIFO.
+        FIFO_CLEAR OFFSET(1) NUMBITS(2) [
+            Rx = 0b01,
+            Tx = 0b10,
+            All = 0b11
+        ]
+    ],
+
+    /// Mini UART Line Control
+    AUX_MU_LCR [
+        /// Mode the UART works in. Only 7 and 8 bit are supported.
+        DATA_SIZE OFFSET(0) NUMBITS(2) [
+            SevenBit = 0b00,
+            EightBit = 0b11
+        ]
+    ],
+
+    /// Mini UART Line Status
+    AUX_MU_LSR [
+        /// This bit is set if the transmit FIFO is empty and the
+        /// transmitter is idle. (Finished shifting out the last bit).
+        TX_IDLE OFFSET(6) NUMBITS(1) [],
+
+        /// This bit is set if the transmit FIFO can accept at least
+        /// one byte.
+        TX_EMPTY OFFSET(5) NUMBITS(1) [],
+
+        /// This bit is set if there was a receiver overrun. That is:
+        /// one or more characters arrived whilst the receive FIFO was
+        /// full. The newly arrived characters have been discarded.
+        /// This bit is cleared each time this register is read.
+        RX_OVERRUN OFFSET(1) NUMBITS(1) [],
+
+        /// This bit is set if the receive FIFO holds at least 1
+        /// symbol.
+        DATA_READY OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Mini UART Extra Control
+    AUX_MU_CNTL [
+        /// If set the transmitter will stop if the CTS line is
+        /// de-asserted.
+        CTS_FLOW OFFSET(3) NUMBITS(1) [],
+
+        /// If set the RTS line will de-assert if the receive FIFO
+        /// reaches its 'auto flow' level.
+        RTS_FLOW OFFSET(2) NUMBITS(1) [],
+
+        /// If this bit is set the mini UART transmitter is enabled.
+        TX_EN OFFSET(1) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// If this bit is set the mini UART receiver is enabled.
+        RX_EN OFFSET(0) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ]
+    ],
+
+    /// Mini UART Baudrate
+    AUX_MU_BAUD [
+        /// mini UART baudrate counter
+        RATE OFFSET(0) NUMBITS(16) []
+    ]
+}
+
+const AUX_BASE: u32 = MMIO_BASE + 0x21_5000;
+
+/// What the boot info calls this UART
+pub const UART_NUMBER: u32 = 1;
+
+/// The baud rate `init` sets up from whatever the core clock is
+pub const BAUD_RATE: u32 = 115_200;
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    __reserved_0: u32,                                  // 0x00
+    AUX_ENABLES: ReadWrite<u32, AUX_ENABLES::Register>, // 0x04
+    __reserved_1: [u32; 14],                            // 0x08
+    AUX_MU_IO: ReadWrite<u32, AUX_MU_IO::Register>,     // 0x40
+    AUX_MU_IER: WriteOnly<u32, AUX_MU_IER::Register>,   // 0x44
+    AUX_MU_IIR: WriteOnly<u32, AUX_MU_IIR::Register>,   // 0x48
+    AUX_MU_LCR: WriteOnly<u32, AUX_MU_LCR::Register>,   // 0x4C
+    AUX_MU_MCR: WriteOnly<u32>,                         // 0x50
+    AUX_MU_LSR: ReadOnly<u32, AUX_MU_LSR::Register>,    // 0x54
+    __reserved_2: [u32; 2],                             // 0x58
+    AUX_MU_CNTL: ReadWrite<u32, AUX_MU_CNTL::Register>, // 0x60
+    __reserved_3: u32,                                  // 0x64
+    AUX_MU_BAUD: WriteOnly<u32, AUX_MU_BAUD::Register>, // 0x68
+}
+
+// Every error `try_getc` has seen since boot
+static mut LINE_ERRORS: LineErrors = LineErrors::new();
+
+// An overrun LSR reported that has not been put on a character yet, or a
+// character the interrupt handler had no room for
+static mut OVERRUN: bool = false;
+
+// Whether the interrupt handler is filling `RX_BUFFER`
+static mut INTERRUPTS: bool = false;
+
+// Characters the interrupt handler received, with `OVERRUN_FLAG` on those
+// that came after lost ones
+static RX_BUFFER: RingBuffer = RingBuffer::new();
+
+const OVERRUN_FLAG: u16 = 1 << 8;
+
+// The core clock `init` found, the baud rate is derived from it
+static mut CLOCK: u32 = 0;
+
+/// Build with `uart_flow_control` to start with RTS/CTS on, the host can still
+/// switch it either way with `Command::FlowControl`.
+pub const DEFAULT_FLOW_CONTROL: bool = cfg!(feature = "uart_flow_control");
+
+pub enum UartError {
+    MailboxError,
+}
+pub type Result<T> = ::core::result::Result<T, UartError>;
+
+pub struct Uart;
+
+impl ops::Deref for Uart {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*Self::ptr() }
+    }
+}
+
+impl Uart {
+    /// The GPU interrupt the AUX block raises, shared with the SPI masters
+    pub const IRQ: usize = 29;
+
+    pub fn new() -> Uart {
+        Uart
+    }
+
+    fn ptr() -> *const RegisterBlock {
+        AUX_BASE as *const _
+    }
+
+    /// The mini UART runs off the core clock, so `_clock_speed` is not used.
+    /// The core clock must not change afterwards, which needs `core_freq` or
+    /// `enable_uart=1` in config.txt.
+    pub fn init(&self, mbox: &mut Mbox, _clock_speed: u32) -> Result<()> {
+        let clock = match mbox.get_clock_rate(Clocks::CORE) {
+            Ok((_, rate)) if rate != 0 => rate,
+            _ => return Err(UartError::MailboxError),
+        };
+        unsafe { write_volatile(&mut CLOCK, clock) };
+
+        self.AUX_ENABLES.modify(AUX_ENABLES::MINI_UART_ENABLE::SET);
+        self.AUX_MU_CNTL.set(0);
+        self.AUX_MU_IER.set(0);
+        self.AUX_MU_LCR.write(AUX_MU_LCR::DATA_SIZE::EightBit); // 8N1
+        self.AUX_MU_MCR.set(0);
+        self.AUX_MU_IIR.write(AUX_MU_IIR::FIFO_CLEAR::All);
+        self.AUX_MU_BAUD
+            .write(AUX_MU_BAUD::RATE.val(clock / (8 * BAUD_RATE) - 1));
+
+        // map UART1 to GPIO pins
+        unsafe {
+            (*gpio::GPFSEL1).modify(gpio::GPFSEL1::FSEL14::TXD1 + gpio::GPFSEL1::FSEL15::RXD1);
+
+            (*gpio::GPPUD).set(0); // enable pins 14 and 15
+            for _ in 0..150 {
+                asm::nop();
+            }
+
+            (*gpio::GPPUDCLK0).modify(
+                gpio::GPPUDCLK0::PUDCLK14::AssertClock + gpio::GPPUDCLK0::PUDCLK15::AssertClock,
+            );
+            for _ in 0..150 {
+                asm::nop();
+            }
+
+            (*gpio::GPPUDCLK0).set(0);
+        }
+
+        self.AUX_MU_CNTL
+            .write(AUX_MU_CNTL::TX_EN::Enabled + AUX_MU_CNTL::RX_EN::Enabled);
+        self.set_flow_control(DEFAULT_FLOW_CONTROL);
+        self.set_software_flow_control(DEFAULT_SOFTWARE_FLOW_CONTROL);
+
+        Ok(())
+    }
+
+    /// The clock the baud rate is derived from, in Hz
+    pub fn clock(&self) -> u32 {
+        unsafe { read_volatile(&CLOCK) }
+    }
+
+    /// Turn RTS/CTS flow control on or off. GPIO16 and 17 are handed to the
+    /// UART as CTS1 and RTS1 while it is on. RTS is dropped once the receive
+    /// FIFO only has 3 spaces left.
+    pub fn set_flow_control(&self, enabled: bool) {
+        self.flush();
+
+        unsafe {
+            if enabled {
+                (*gpio::GPFSEL1).modify(gpio::GPFSEL1::FSEL16::CTS1 + gpio::GPFSEL1::FSEL17::RTS1);
+            } else {
+                (*gpio::GPFSEL1)
+                    .modify(gpio::GPFSEL1::FSEL16::Input + gpio::GPFSEL1::FSEL17::Input);
+            }
+        }
+
+        if enabled {
+            self.AUX_MU_CNTL
+                .modify(AUX_MU_CNTL::RTS_FLOW::SET + AUX_MU_CNTL::CTS_FLOW::SET);
+        } else {
+            self.AUX_MU_CNTL
+                .modify(AUX_MU_CNTL::RTS_FLOW::CLEAR + AUX_MU_CNTL::CTS_FLOW::CLEAR);
+        }
+    }
+
+    /// Whether `init` has run
+    pub fn is_enabled(&self) -> bool {
+        self.AUX_ENABLES.is_set(AUX_ENABLES::MINI_UART_ENABLE)
+            && self.AUX_MU_CNTL.is_set(AUX_MU_CNTL::TX_EN)
+    }
+
+    /// Wait until everything sent so far has left the shift register
+    pub fn flush(&self) {
+        loop {
+            if self.line_status().is_set(AUX_MU_LSR::TX_IDLE) {
+                break;
+            }
+
+            asm::nop();
+        }
+    }
+
+    /// Whether a character is waiting to be received
+    pub fn can_read(&self) -> bool {
+        self.stashed() > 0
+            || !RX_BUFFER.is_empty()
+            || self.line_status().is_set(AUX_MU_LSR::DATA_READY)
+    }
+
+    /// Reading LSR clears the overrun flag, so every read goes through here
+    /// to keep it for the next character
+    fn line_status(&self) -> LocalRegisterCopy<u32, AUX_MU_LSR::Register> {
+        let lsr = self.AUX_MU_LSR.extract();
+        if lsr.is_set(AUX_MU_LSR::RX_OVERRUN) {
+            unsafe { write_volatile(&mut OVERRUN, true) };
+        }
+
+        lsr
+    }
+
+    // Read IO, flagging the character if it follows lost ones
+    fn take_data(&self) -> u16 {
+        let mut data = self.AUX_MU_IO.read(AUX_MU_IO::DATA) as u16;
+
+        if unsafe { read_volatile(&OVERRUN) } {
+            data |= OVERRUN_FLAG;
+            unsafe { write_volatile(&mut OVERRUN, false) };
+        }
+
+        data
+    }
+
+    /// Have the receive interrupt move characters into a buffer as soon as
+    /// they arrive, so none are lost while whoever reads them is busy with
+    /// something else. IRQs have to be unmasked as well, see
+    /// `exception::unmask_irqs`.
+    pub fn set_interrupts(&self, enabled: bool) {
+        let intc = Intc::new();
+
+        if enabled {
+            unsafe { write_volatile(&mut INTERRUPTS, true) };
+            self.AUX_MU_IER.write(AUX_MU_IER::RX_INT::SET);
+            intc.enable(Uart::IRQ);
+        } else {
+            intc.disable(Uart::IRQ);
+            self.AUX_MU_IER.set(0);
+            unsafe { write_volatile(&mut INTERRUPTS, false) };
+        }
+    }
+
+    /// Move everything in the receive FIFO into the receive buffer, called by
+    /// the IRQ handler. The interrupt goes away once the FIFO is empty.
+    pub fn handle_interrupt(&self) {
+        while self.line_status().is_set(AUX_MU_LSR::DATA_READY) {
+            let data = self.take_data();
+
+            if RX_BUFFER.push(data).is_err() {
+                unsafe { write_volatile(&mut OVERRUN, true) };
+            }
+        }
+    }
+
+    /// The receive errors counted by `try_getc` since boot
+    pub fn line_errors(&self) -> LineErrors {
+        unsafe { read_volatile(&LINE_ERRORS) }
+    }
+}
+
+impl SoftwareFlow for Uart {
+    fn put(&self, c: u8) {
+        // wait until we can send
+        loop {
+            if self.line_status().is_set(AUX_MU_LSR::TX_EMPTY) {
+                break;
+            }
+
+            asm::nop();
+        }
+
+        // write the character to the buffer
+        self.AUX_MU_IO.set(u32::from(c));
+    }
+
+    /// With `OVERRUN_FLAG` if characters were lost before it. While the
+    /// interrupt handler is filling the receive buffer, that is the only
+    /// place to take it from, or the order gets mixed up.
+    fn get(&self) -> Option<u16> {
+        if let Some(data) = RX_BUFFER.pop() {
+            return Some(data);
+        }
+
+        if unsafe { read_volatile(&INTERRUPTS) }
+            || !self.line_status().is_set(AUX_MU_LSR::DATA_READY)
+        {
+            return None;
+        }
+
+        Some(self.take_data())
+    }
+
+    fn buffered(&self) -> usize {
+        RX_BUFFER.len()
+    }
+}
+
+impl Receive for Uart {
+    fn try_getc(&self) -> core::result::Result<u8, RxError> {
+        let data = match self.receive() {
+            Some(data) => data,
+            None => return Err(RxError::Empty),
+        };
+        let c = data as u8;
+
+        if data & OVERRUN_FLAG != 0 {
+            let mut errors = self.line_errors();
+            errors.overruns += 1;
+            unsafe { write_volatile(&mut LINE_ERRORS, errors) };
+
+            Err(RxError::Overrun(c))
+        } else {
+            Ok(c)
+        }
+    }
+}
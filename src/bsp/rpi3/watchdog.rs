@@ -0,0 +1,143 @@
+use super::MMIO_BASE;
+use core::ops;
+use cortex_a::asm;
+use register::{mmio::ReadWrite, register_bitfields};
+
+// Power management watchdog registers.
+//
+// The BCM2837 datasheet does not document these, the layout follows the
+// bcm2835_wdt driver in Linux.
+register_bitfields! {
+    u32,
+
+    /// Reset Control
+    RSTC [
+        /// Every write must carry the password or it is ignored
+        PASSWD OFFSET(24) NUMBITS(8) [
+            Password = 0x5A
+        ],
+
+        /// What happens when the watchdog expires
+        WRCFG OFFSET(4) NUMBITS(2) [
+            Clear = 0b00,
+            FullReset = 0b10
+        ],
+
+        /// Writing this value on its own stops a running watchdog
+        RESET OFFSET(0) NUMBITS(12) [
+            Stop = 0x102
+        ]
+    ],
+
+    /// Reset Status
+    RSTS [
+        PASSWD OFFSET(24) NUMBITS(8) [
+            Password = 0x5A
+        ],
+
+        /// The partition the firmware boots from after a reset. The bits are
+        /// spread out over every other bit, 0 is the default boot partition.
+        PARTITION OFFSET(0) NUMBITS(11) []
+    ],
+
+    /// Watchdog timer
+    WDOG [
+        PASSWD OFFSET(24) NUMBITS(8) [
+            Password = 0x5A
+        ],
+
+        /// Time until expiry, in 1/65536 s
+        TIME OFFSET(0) NUMBITS(20) []
+    ]
+}
+
+const PM_BASE: u32 = MMIO_BASE + 0x10_0000;
+
+// The watchdog counter is 20 bits of 16.16 fixed point seconds
+const TICKS_PER_SECOND: u32 = 1 << 16;
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    __reserved_0: [u32; 7],               // 0x00
+    RSTC: ReadWrite<u32, RSTC::Register>, // 0x1C
+    RSTS: ReadWrite<u32, RSTS::Register>, // 0x20
+    WDOG: ReadWrite<u32, WDOG::Register>, // 0x24
+}
+
+pub struct Watchdog {
+    ticks: u32,
+}
+
+impl ops::Deref for Watchdog {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*Self::ptr() }
+    }
+}
+
+impl Watchdog {
+    /// The longest timeout the 20 bit counter can hold, 15 s
+    pub const MAX_TIMEOUT_SECS: u32 = 0xF_FFFF / TICKS_PER_SECOND;
+
+    pub fn new() -> Watchdog {
+        Watchdog { ticks: 0 }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr() -> *const RegisterBlock {
+        PM_BASE as *const _
+    }
+
+    /// Arm the watchdog, the board resets `secs` seconds from now unless it is
+    /// fed in the meantime. Timeouts are clamped to `MAX_TIMEOUT_SECS`, callers
+    /// that take them from the host check them first.
+    pub fn start(&mut self, secs: u32) {
+        let secs = if secs > Watchdog::MAX_TIMEOUT_SECS {
+            Watchdog::MAX_TIMEOUT_SECS
+        } else {
+            secs
+        };
+
+        self.ticks = secs * TICKS_PER_SECOND;
+        self.arm(self.ticks);
+    }
+
+    /// Push the expiry back to a full timeout again
+    pub fn feed(&self) {
+        if self.ticks != 0 {
+            self.arm(self.ticks);
+        }
+    }
+
+    /// Disarm the watchdog
+    pub fn stop(&mut self) {
+        self.ticks = 0;
+        self.RSTC.write(RSTC::PASSWD::Password + RSTC::RESET::Stop);
+    }
+
+    /// Reset the board. The partition is cleared, so the firmware boots the
+    /// default image again, which puts us straight back into the bootloader.
+    pub fn reset(&mut self) -> ! {
+        // Only every other bit belongs to the partition number
+        let partition = self.RSTS.read(RSTS::PARTITION) & !0x555;
+        self.RSTS
+            .modify(RSTS::PASSWD::Password + RSTS::PARTITION.val(partition));
+
+        // Give the write a few ticks to settle before pulling the plug
+        self.ticks = 10;
+        self.arm(self.ticks);
+
+        loop {
+            asm::wfe();
+        }
+    }
+
+    fn arm(&self, ticks: u32) {
+        self.WDOG
+            .write(WDOG::PASSWD::Password + WDOG::TIME.val(ticks));
+        self.RSTC
+            .modify(RSTC::PASSWD::Password + RSTC::WRCFG::FullReset);
+    }
+}
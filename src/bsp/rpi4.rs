@@ -13,18 +13,17 @@ use cortex_a::{asm, regs::*};
 ///
 /// # Safety
 ///
-/// - Linker script must ensure to place this function at `0x80_000`.
+/// - Linker script must ensure to place this function at `layout::LOAD_ADDR`.
 #[no_mangle]
 pub unsafe extern "C" fn _start() -> ! {
-    use crate::runtime_init;
+    use crate::{layout, runtime_init};
 
     const CORE_0: u64 = 0;
     const CORE_MASK: u64 = 0x3;
-    const STACK_START: u64 = 0x80_000;
 
     if CORE_0 == MPIDR_EL1.get() & CORE_MASK {
-        SP.set(STACK_START);
-        runtime_init::init()
+        SP.set(layout::STACK_TOP);
+        runtime_init::init(0)
     } else {
         // if not core0, infinitely wait for events
         loop {
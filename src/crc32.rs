@@ -0,0 +1,51 @@
+//! CRC-32 as used by zlib and friends, so the host side can use whatever its
+//! standard library offers.
+//!
+//! A table of 16 entries is used instead of the usual 256, it is a quarter of
+//! the speed but does not need to be computed at runtime or take up 1 KiB of
+//! the bootloader.
+
+const TABLE: [u32; 16] = [
+    0x0000_0000,
+    0x1DB7_1064,
+    0x3B6E_20C8,
+    0x26D9_30AC,
+    0x76DC_4190,
+    0x6B6B_51F4,
+    0x4DB2_6158,
+    0x5005_713C,
+    0xEDB8_8320,
+    0xF00F_9344,
+    0xD6D6_A3E8,
+    0xCB61_B38C,
+    0x9B64_C2B0,
+    0x86D3_D2D4,
+    0xA00A_E278,
+    0xBDBD_F21C,
+];
+
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = TABLE[((self.0 ^ u32::from(byte)) & 0xF) as usize] ^ (self.0 >> 4);
+            self.0 = TABLE[((self.0 ^ (u32::from(byte) >> 4)) & 0xF) as usize] ^ (self.0 >> 4);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+/// CRC-32 of a whole buffer
+pub fn checksum(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
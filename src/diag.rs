@@ -0,0 +1,111 @@
+//! Serial link diagnostics, used to qualify an adapter and cable at a given
+//! baud rate before trusting uploads to it.
+//!
+//! The host selects a test with `Command::Diagnostics` followed by the mode,
+//! the number of bytes it is going to send and a seed. Once the bytes are
+//! through, or once the host has been quiet for `protocol::BYTE_TIMEOUT`, the
+//! board sends a report of seven little endian words: the bytes received, the
+//! bytes and the bits that did not match the pattern, then the framing,
+//! parity, break and overrun counts from the UART in use. The pattern counts
+//! are always 0 in echo mode, there the host does the comparison. The mini
+//! UART only notices overruns, with it the other three are always 0.
+//!
+//! The self-test mode ignores the length and the seed, it runs the PL011
+//! loopback test and reports a single word, 1 if it passed. It does not run
+//! by itself after the first boot, see `kernel_entry`.
+
+use crate::bsp::{Receive, RxError, SoftwareFlow, Uart};
+use crate::protocol;
+
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum Mode {
+    /// Every byte is sent straight back
+    Echo = 0,
+    /// The host sends the xorshift32 sequence for the seed, the board checks it
+    Pattern = 1,
+    /// `Uart::self_test`, the mini UART has no loopback mode
+    #[cfg(not(feature = "mini_uart"))]
+    SelfTest = 2,
+}
+
+// This is a hack because we are on no_std
+impl Mode {
+    pub fn from(value: u32) -> Option<Mode> {
+        match value {
+            0 => Some(Mode::Echo),
+            1 => Some(Mode::Pattern),
+            #[cfg(not(feature = "mini_uart"))]
+            2 => Some(Mode::SelfTest),
+            _ => None,
+        }
+    }
+}
+
+/// xorshift32, cheap enough to keep up at any baud rate and trivial to
+/// reproduce on the host
+struct Pattern(u32);
+
+impl Pattern {
+    fn new(seed: u32) -> Pattern {
+        // xorshift gets stuck on 0
+        Pattern(if seed == 0 { 1 } else { seed })
+    }
+
+    fn next(&mut self) -> u8 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+
+        self.0 as u8
+    }
+}
+
+pub fn run(uart: &Uart, mode: Mode, length: u32, seed: u32) {
+    #[cfg(not(feature = "mini_uart"))]
+    {
+        if let Mode::SelfTest = mode {
+            protocol::send_u32(uart, uart.self_test() as u32);
+            return;
+        }
+    }
+
+    let before = uart.line_errors();
+    let mut pattern = Pattern::new(seed);
+    let mut byte_errors = 0;
+    let mut bit_errors = 0;
+    let mut received = 0;
+
+    while received < length {
+        let c = match uart.getc_timeout(protocol::BYTE_TIMEOUT) {
+            Ok(c) => c,
+            Err(RxError::Empty) => break,
+            Err(e) => e.data().unwrap_or(0),
+        };
+        received += 1;
+
+        match mode {
+            Mode::Echo => uart.send(c as char),
+            #[cfg(not(feature = "mini_uart"))]
+            Mode::SelfTest => unreachable!(),
+            Mode::Pattern => {
+                let diff = c ^ pattern.next();
+
+                if diff != 0 {
+                    byte_errors += 1;
+                    bit_errors += diff.count_ones();
+                }
+            }
+        }
+    }
+
+    let errors = uart.line_errors().since(&before);
+
+    protocol::send_u32(uart, received);
+    protocol::send_u32(uart, byte_errors);
+    protocol::send_u32(uart, bit_errors);
+    protocol::send_u32(uart, errors.framing);
+    protocol::send_u32(uart, errors.parity);
+    protocol::send_u32(uart, errors.breaks);
+    protocol::send_u32(uart, errors.overruns);
+}
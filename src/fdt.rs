@@ -0,0 +1,170 @@
+//! Just enough of a flattened device tree parser to patch values in place.
+//!
+//! Growing the tree would mean moving the strings block around, so we only
+//! ever overwrite properties that are already there. Everything is read and
+//! written a byte at a time, with the MMU off unaligned accesses fault and
+//! 64-bit values in the tree are only 4 byte aligned.
+
+use core::ptr::{read_volatile, write_volatile};
+
+const MAGIC: u32 = 0xD00D_FEED;
+
+// Structure block tokens
+const BEGIN_NODE: u32 = 1;
+const END_NODE: u32 = 2;
+const PROP: u32 = 3;
+const NOP: u32 = 4;
+const END: u32 = 9;
+
+pub struct Fdt {
+    base: usize,
+}
+
+impl Fdt {
+    /// Check for a device tree at `addr`.
+    ///
+    /// # Safety
+    ///
+    /// - `addr` must be 0 or readable memory.
+    pub unsafe fn from_addr(addr: usize) -> Option<Fdt> {
+        if addr == 0 || addr % 4 != 0 {
+            return None;
+        }
+
+        let fdt = Fdt { base: addr };
+        if fdt.read_u32(0) != MAGIC {
+            return None;
+        }
+
+        Some(fdt)
+    }
+
+    pub fn addr(&self) -> usize {
+        self.base
+    }
+
+    /// Size of the whole blob in bytes
+    pub fn size(&self) -> usize {
+        self.read_u32(4) as usize
+    }
+
+    fn read_u8(&self, offset: usize) -> u8 {
+        unsafe { read_volatile((self.base + offset) as *const u8) }
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        (0..4).fold(0, |value, i| {
+            (value << 8) | u32::from(self.read_u8(offset + i))
+        })
+    }
+
+    fn write_u64(&mut self, offset: usize, value: u64) {
+        for i in 0..8 {
+            unsafe {
+                write_volatile(
+                    (self.base + offset + i) as *mut u8,
+                    (value >> (56 - i * 8)) as u8,
+                );
+            }
+        }
+    }
+
+    /// Whether the NUL terminated string at `offset` equals `s`
+    fn str_eq(&self, offset: usize, s: &str) -> bool {
+        s.bytes()
+            .chain(core::iter::once(0))
+            .enumerate()
+            .all(|(i, c)| self.read_u8(offset + i) == c)
+    }
+
+    fn str_len(&self, offset: usize) -> usize {
+        let mut len = 0;
+        while self.read_u8(offset + len) != 0 {
+            len += 1;
+        }
+
+        len
+    }
+
+    /// Point `cpu-release-addr` of every cpu node at `release_addr(reg)`. Cpus
+    /// without the property, or for which `release_addr` returns `None`, are
+    /// left alone. Returns how many were patched.
+    pub fn set_cpu_release_addrs<F>(&mut self, release_addr: F) -> usize
+    where
+        F: Fn(usize) -> Option<u64>,
+    {
+        let structs = self.read_u32(8) as usize;
+        let strings = self.read_u32(12) as usize;
+
+        let mut offset = structs;
+        let mut depth = 0;
+        let mut in_cpus = false;
+        let mut in_cpu = false;
+        // Value offsets of `reg` and `cpu-release-addr` in the current cpu node
+        let mut reg = None;
+        let mut release = None;
+        let mut patched = 0;
+
+        loop {
+            let token = self.read_u32(offset);
+            offset += 4;
+
+            match token {
+                BEGIN_NODE => {
+                    depth += 1;
+                    if depth == 2 {
+                        in_cpus = self.str_eq(offset, "cpus");
+                    } else if depth == 3 && in_cpus {
+                        in_cpu = true;
+                        reg = None;
+                        release = None;
+                    }
+
+                    offset += self.str_len(offset) + 1;
+                }
+                END_NODE => {
+                    if depth == 3 && in_cpu {
+                        if let (Some(reg), Some(release)) = (reg, release) {
+                            let core = self.read_u32(reg) as usize;
+                            if let Some(addr) = release_addr(core) {
+                                self.write_u64(release, addr);
+                                patched += 1;
+                            }
+                        }
+                        in_cpu = false;
+                    }
+                    if depth == 2 {
+                        in_cpus = false;
+                    }
+
+                    depth -= 1;
+                }
+                PROP => {
+                    let len = self.read_u32(offset) as usize;
+                    let name = strings + self.read_u32(offset + 4) as usize;
+                    offset += 8;
+
+                    if in_cpu {
+                        // On AArch64 reg is usually two cells, the core is in the last one
+                        if self.str_eq(name, "reg") && len >= 4 {
+                            reg = Some(offset + len - 4);
+                        } else if self.str_eq(name, "cpu-release-addr") && len == 8 {
+                            release = Some(offset);
+                        }
+                    }
+
+                    offset += len;
+                }
+                NOP => {}
+                END => break,
+                // Not a tree we understand, stop before doing any damage
+                _ => break,
+            }
+
+            // Tokens are 4 byte aligned
+            offset = (offset + 3) & !3;
+        }
+
+        patched
+    }
+}
@@ -0,0 +1,7 @@
+//! Memory layout of the selected board, generated by `build.rs` from the same
+//! description as the linker script.
+
+// The generated addresses come without digit separators
+#![allow(clippy::unreadable_literal)]
+
+include!(concat!(env!("OUT_DIR"), "/layout.rs"));
@@ -0,0 +1,419 @@
+//! Receiving kernel images from the host.
+//!
+//! Besides the legacy upload, where the host sends the size and then the
+//! image, an image can be sent with `Command::Load`. Those carry an id chosen
+//! by the host and end with a CRC-32 over the whole image. If the host stalls
+//! halfway through, the board drops back to the handshake and keeps track of
+//! how much has been committed, so the host can pick up where it left off with
+//! `Command::Resume`.
+//!
+//! `Command::Delta` works like `Load`, except that the board first sends the
+//! number of `DELTA_BLOCK_SIZE` blocks in the image, followed by the CRC-32 of
+//! each block as it currently is in memory. The host answers with the index of
+//! every block that differs followed by its contents, and `DELTA_END` once it
+//! is done. The last block is cut short at the end of the image.
+//!
+//! Images go to `LOAD_ADDR` unless the host picks another address with
+//! `Command::LoadAddress`. Either way an upload that would overwrite the
+//! bootloader, the peripherals, the device tree or memory the firmware needs,
+//! or that does not lie in ARM memory, is refused, see `fits`.
+
+use crate::bsp::{self, ExceptionLevel, Receive, RxError, SoftwareFlow, Timer, Uart, Watchdog};
+use crate::crc32;
+use crate::fdt::Fdt;
+use crate::layout;
+use crate::protocol;
+use core::fmt;
+use core::time::Duration;
+
+/// Where the firmware would have put the kernel, and where we put it instead
+/// by default
+pub const LOAD_ADDR: usize = layout::LOAD_ADDR as usize;
+
+pub const DELTA_BLOCK_SIZE: u32 = 4096;
+/// Block index that ends a delta upload
+pub const DELTA_END: u32 = 0xFFFF_FFFF;
+
+/// How long the host may be quiet during an upload before we give up on it
+/// and go back to the handshake. More lenient than within a command, the host
+/// may be reading the image from slow storage as it goes.
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(10 * protocol::BYTE_TIMEOUT.as_secs());
+
+/// Options the host picked during the handshake
+pub struct Settings {
+    /// Seconds without a byte before the watchdog resets the board during an
+    /// upload, 0 to leave it to `STALL_TIMEOUT`. Anything from `STALL_TIMEOUT`
+    /// up would never fire.
+    pub transfer_timeout: u32,
+    /// Bytes between progress markers, 0 for none
+    pub progress_interval: u32,
+    /// Where the kernel is entered
+    pub handoff_level: ExceptionLevel,
+    /// Where the next image goes, and where it is started
+    pub load_addr: usize,
+}
+
+impl Settings {
+    pub fn new() -> Settings {
+        Settings {
+            transfer_timeout: 0,
+            progress_interval: 0,
+            handoff_level: bsp::DEFAULT_HANDOFF,
+            load_addr: LOAD_ADDR,
+        }
+    }
+
+    /// Start the watchdog for an upload, if the host asked for it
+    pub fn arm_watchdog(&self, watchdog: &mut Watchdog) {
+        if self.transfer_timeout != 0 {
+            watchdog.start(self.transfer_timeout);
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    Legacy,
+    Full,
+    Delta,
+}
+
+/// Memory an upload has to stay inside of or clear of, on top of what the
+/// bootloader knows about itself
+#[derive(Clone, Copy)]
+pub struct Bounds {
+    /// Start and end of ARM memory
+    pub memory: (u64, u64),
+    /// Start and end of the device tree the kernel gets, if there is one
+    pub device_tree: Option<(u64, u64)>,
+}
+
+impl Bounds {
+    /// If the firmware did not tell us how much ARM memory there is, all of it
+    /// below the peripherals is taken for it
+    pub fn new(arm_memory: Option<(u32, u32)>, device_tree: Option<&Fdt>) -> Bounds {
+        let memory = match arm_memory {
+            Some((start, size)) => (u64::from(start), u64::from(start) + u64::from(size)),
+            None => (0, bsp::PERIPHERAL_MEMORY.0),
+        };
+        let device_tree = device_tree.map(|fdt| {
+            let start = fdt.addr() as u64;
+            (start, start + fdt.size() as u64)
+        });
+
+        Bounds {
+            memory,
+            device_tree,
+        }
+    }
+}
+
+/// Whether `size` bytes at `addr` lie in ARM memory and stay clear of the
+/// bootloader, its stack, the peripherals, the device tree and the memory the
+/// firmware reserved
+pub fn fits(addr: usize, size: u32, bounds: &Bounds) -> bool {
+    let start = addr as u64;
+    let end = match start.checked_add(u64::from(size)) {
+        Some(end) => end,
+        None => return false,
+    };
+    let overlaps = |&(other_start, other_end): &(u64, u64)| start < other_end && other_start < end;
+
+    start >= bounds.memory.0
+        && end <= bounds.memory.1
+        && !overlaps(&bsp::bootloader_memory())
+        && !overlaps(&bsp::PERIPHERAL_MEMORY)
+        && !bounds.device_tree.iter().any(overlaps)
+        && !layout::RESERVED.iter().any(overlaps)
+}
+
+/// The image being received
+pub struct Image {
+    id: u32,
+    addr: usize,
+    size: u32,
+    committed: u32,
+    kind: Kind,
+}
+
+impl Image {
+    /// An image sent the way the original raspbootin expects
+    pub fn legacy(addr: usize, size: u32) -> Image {
+        Image::new(Kind::Legacy, 0, addr, size)
+    }
+
+    /// An image sent with `Command::Load` or `Command::Delta`
+    pub fn new(kind: Kind, id: u32, addr: usize, size: u32) -> Image {
+        Image {
+            id,
+            addr,
+            size,
+            committed: 0,
+            kind,
+        }
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// See `fits`
+    pub fn fits(&self, bounds: &Bounds) -> bool {
+        fits(self.addr, self.size, bounds)
+    }
+
+    /// Bytes of the image with the given id that are already in memory
+    pub fn committed(&self, id: u32) -> u32 {
+        if self.kind != Kind::Legacy && self.id == id {
+            self.committed
+        } else {
+            0
+        }
+    }
+
+    /// Continue receiving the image with the given id at `offset`. Fails if
+    /// that is not the image we have, or we do not have that much of it.
+    pub fn resume(&mut self, id: u32, offset: u32) -> Result<(), ResumeError> {
+        if self.kind == Kind::Legacy || self.id != id {
+            return Err(ResumeError::UnknownImage);
+        }
+        if offset > self.committed {
+            return Err(ResumeError::Behind(self.committed));
+        }
+
+        // Whatever is missing is sent in full from here on
+        self.kind = Kind::Full;
+        self.committed = offset;
+        Ok(())
+    }
+
+    /// Forget about what has been received, e.g. after a failed check
+    pub fn discard(&mut self) {
+        self.committed = 0;
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.addr as *const u8, self.size as usize) }
+    }
+
+    /// Check the host's CRC-32 against the whole image in memory
+    pub fn verify(&self, crc: u32) -> bool {
+        self.committed == self.size && crc32::checksum(self.as_slice()) == crc
+    }
+
+    fn blocks(&self) -> u32 {
+        // Rounding up by adding first would overflow for sizes near 4 GiB
+        self.size / DELTA_BLOCK_SIZE + (self.size % DELTA_BLOCK_SIZE != 0) as u32
+    }
+
+    /// Start and end offset of a delta block
+    fn block(&self, index: u32) -> (u32, u32) {
+        let start = index * DELTA_BLOCK_SIZE;
+        let end = start.saturating_add(DELTA_BLOCK_SIZE);
+
+        (start, if end > self.size { self.size } else { end })
+    }
+}
+
+/// Why `Image::resume` turned the host down
+#[derive(Clone, Copy)]
+pub enum ResumeError {
+    /// There is no image with that id to resume, or it was a legacy upload
+    UnknownImage,
+    /// We have fewer bytes of the image than the host asked to resume at
+    Behind(u32),
+}
+
+/// Why `receive` gave up on an upload
+#[derive(Clone, Copy)]
+pub enum Error {
+    /// The host was quiet for longer than `STALL_TIMEOUT`
+    Stalled,
+    /// The UART flagged a byte as bad. The host has stopped sending by the
+    /// time this is returned.
+    Line(RxError),
+    /// The host sent a delta block index that does not exist
+    OutOfSync,
+}
+
+/// How fast the last transfer went
+pub struct Throughput {
+    bytes: u32,
+    micros: u64,
+}
+
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rate = if self.micros != 0 {
+            u64::from(self.bytes) * 1_000_000 / self.micros
+        } else {
+            0
+        };
+
+        write!(
+            f,
+            "{} bytes in {}.{:03} s, {} B/s",
+            self.bytes,
+            self.micros / 1_000_000,
+            (self.micros / 1_000) % 1_000,
+            rate
+        )
+    }
+}
+
+/// Byte level receiving shared by all kinds of uploads
+struct Transfer<'a> {
+    uart: &'a Uart,
+    timer: &'a Timer,
+    watchdog: &'a Watchdog,
+    settings: &'a Settings,
+    start: u64,
+    received: u32,
+}
+
+impl<'a> Transfer<'a> {
+    fn getc(&mut self) -> Result<u8, Error> {
+        let c = match self.uart.getc_timeout(STALL_TIMEOUT) {
+            Ok(c) => c,
+            Err(RxError::Empty) => return Err(Error::Stalled),
+            Err(e) => {
+                self.drain();
+                return Err(Error::Line(e));
+            }
+        };
+        self.received += 1;
+        self.watchdog.feed();
+
+        if self.settings.progress_interval != 0
+            && self.received % self.settings.progress_interval == 0
+        {
+            self.uart.send(protocol::PROGRESS_MARKER);
+        }
+
+        Ok(c)
+    }
+
+    /// Throw away whatever the host still sends, until it has been quiet for
+    /// a tenth of a second
+    fn drain(&self) {
+        let quiet = self.timer.frequency() / 10;
+        let mut last = self.timer.ticks();
+
+        while self.timer.ticks() - last < quiet {
+            if self.uart.can_read() {
+                self.uart.getc();
+                self.watchdog.feed();
+                last = self.timer.ticks();
+            }
+        }
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        let mut value: u32 = 0;
+        for i in 0..4 {
+            value |= u32::from(self.getc()?) << (i * 8);
+        }
+
+        Ok(value)
+    }
+
+    /// Receive the part of `image` between two offsets
+    fn fill(&mut self, image: &Image, start: u32, end: u32) -> Result<(), Error> {
+        let base = image.addr as *mut u8;
+        for offset in start..end {
+            unsafe {
+                *base.offset(offset as isize) = self.getc()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn throughput(&self) -> Throughput {
+        Throughput {
+            bytes: self.received,
+            micros: self.timer.ticks_to_micros(self.timer.ticks() - self.start),
+        }
+    }
+}
+
+/// Receive the rest of `image`. The watchdog is fed on every byte.
+///
+/// The transfer gives up once the host has been quiet for `STALL_TIMEOUT`, and
+/// stops at the first byte the UART flags as bad. Everything received before
+/// that stays committed, unless it was a delta upload.
+pub fn receive(
+    uart: &Uart,
+    timer: &Timer,
+    watchdog: &Watchdog,
+    settings: &Settings,
+    image: &mut Image,
+) -> Result<Throughput, Error> {
+    let mut transfer = Transfer {
+        uart,
+        timer,
+        watchdog,
+        settings,
+        start: timer.ticks(),
+        received: 0,
+    };
+
+    match image.kind {
+        Kind::Legacy | Kind::Full => {
+            let base = image.addr as *mut u8;
+            while image.committed < image.size {
+                let c = transfer.getc()?;
+                unsafe {
+                    *base.offset(image.committed as isize) = c;
+                }
+                image.committed += 1;
+            }
+        }
+        Kind::Delta => {
+            image.committed = 0;
+
+            protocol::send_u32(uart, image.blocks());
+            for index in 0..image.blocks() {
+                // Hashing a large image takes longer than the watchdog waits
+                watchdog.feed();
+
+                let (start, end) = image.block(index);
+                protocol::send_u32(
+                    uart,
+                    crc32::checksum(&image.as_slice()[start as usize..end as usize]),
+                );
+            }
+
+            loop {
+                let index = transfer.read_u32()?;
+                if index == DELTA_END {
+                    break;
+                }
+
+                // A bogus index means we are out of sync with the host
+                if index >= image.blocks() {
+                    return Err(Error::OutOfSync);
+                }
+
+                let (start, end) = image.block(index);
+                transfer.fill(image, start, end)?;
+            }
+
+            image.committed = image.size;
+        }
+    }
+
+    Ok(transfer.throughput())
+}
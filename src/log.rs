@@ -0,0 +1,74 @@
+//! Leveled logging to the UART.
+//!
+//! The most verbose level that gets printed is picked at build time with the
+//! `RASPBOOTIN_LOG` environment variable (`error`, `warn`, `info` or `debug`,
+//! `info` by default). While the host is sending binary data the log is kept
+//! quiet, see `set_quiet`.
+
+use core::fmt;
+use core::ptr::{read_volatile, write_volatile};
+
+// Not every level is in use at every point in time
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+
+include!(concat!(env!("OUT_DIR"), "/log_level.rs"));
+
+static mut QUIET: bool = false;
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// Keep log messages off the UART, so they do not end up in the middle of a
+/// transfer
+pub fn set_quiet(quiet: bool) {
+    unsafe { write_volatile(&mut QUIET, quiet) }
+}
+
+/// Whether a message at `level` would be printed right now
+pub fn enabled(level: Level) -> bool {
+    level <= MAX_LEVEL && !unsafe { read_volatile(&QUIET) }
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, args: fmt::Arguments) {
+    if enabled(level) {
+        crate::print::_print(format_args_nl!("[{}] {}", level, args));
+    }
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::Level::Error, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::Level::Warn, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::Level::Info, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log::_log($crate::log::Level::Debug, format_args!($($arg)*)));
+}
@@ -1,45 +1,367 @@
 #![feature(format_args_nl)]
+#![feature(global_asm)]
 #![feature(panic_info_message)]
 #![no_main]
 #![no_std]
 
+#[macro_use]
+mod print;
+#[macro_use]
+mod log;
+
+mod board_info;
+mod boot_info;
 mod bsp;
+mod crc32;
+mod diag;
+mod fdt;
+mod layout;
+mod loader;
+mod protocol;
+mod ring_buffer;
 
 mod runtime_init;
 
+use bsp::SoftwareFlow;
 use cortex_a::asm;
+use protocol::Command;
+
+// The PL011 clock we ask the firmware for, bsp::BAUD_RATE depends on it. The
+// mini UART runs off the core clock instead.
+const UART_CLOCK: u32 = 4_000_000;
+
+// Set once the first boot has brought up the UART. It lives in .data so
+// a kernel jumping back into us finds it set, the .bss is zeroed every time.
+#[link_section = ".data"]
+static mut BOOTED: bool = false;
+
+/// Bring up the UART and have it receive in the background
+fn init_uart(uart: &bsp::Uart, mbox: &mut bsp::mbox::Mbox) {
+    // Without a UART there is no host to talk to, and nothing to boot
+    if uart.init(mbox, UART_CLOCK).is_err() {
+        loop {
+            asm::wfe();
+        }
+    }
+
+    // Only on a cold boot, a kernel jumping back into us usually has a host
+    // already talking to us and the loopback would eat what it sends. The
+    // host can still ask for it, see `diag::Mode::SelfTest`. The mini UART
+    // has no loopback mode to test with.
+    #[cfg(not(feature = "mini_uart"))]
+    {
+        if unsafe { !core::ptr::read_volatile(&BOOTED) } && !uart.self_test() {
+            warn!("UART loopback self-test failed");
+        }
+    }
+    unsafe { core::ptr::write_volatile(&mut BOOTED, true) };
+
+    // Receive in the background, hashing or decompressing an image takes
+    // longer than the FIFO lasts at high baud rates
+    uart.set_interrupts(true);
+    bsp::exception::unmask_irqs();
+}
+
+fn kernel_entry(device_tree: usize) -> ! {
+    let timer = bsp::Timer::new();
+    let start_ticks = timer.ticks();
+
+    bsp::exception::install();
 
-fn kernel_entry() -> ! {
     let mut mbox = bsp::mbox::Mbox::new();
     let uart = bsp::Uart::new();
+    let mut watchdog = bsp::Watchdog::new();
 
-    if uart.init(&mut mbox, 4_000_000).is_err() {
-        asm::wfe();
-    }
+    // A kernel jumping back into us may have left either of these running
+    mbox.flush();
+    watchdog.stop();
+
+    init_uart(&uart, &mut mbox);
 
-    for c in "RBIN64\r\n".chars() {
-        uart.send(c);
+    let board_info = board_info::BoardInfo::collect(&mut mbox);
+    let mut command_line = [0; 1024];
+    let command_line_len = mbox
+        .get_command_line(&mut command_line)
+        .unwrap_or_else(|_| {
+            warn!("Could not get the command line from the firmware");
+            0
+        });
+
+    let mut device_tree = unsafe { fdt::Fdt::from_addr(device_tree) };
+    let bounds = loader::Bounds::new(board_info.arm_memory, device_tree.as_ref());
+
+    let (start, end) = bsp::bootloader_memory();
+    debug!("Running from {:#x}-{:#x}", start, end);
+    if !loader::fits(loader::LOAD_ADDR, 1, &bounds) {
+        warn!(
+            "Could not move out of the way, uploads need a load address outside {:#x}-{:#x}",
+            start, end
+        );
     }
 
-    uart.send(3 as char);
-    uart.send(3 as char);
-    uart.send(3 as char);
+    // Receiving and checking the image is much faster with the caches on.
+    // The mailbox is off limits until they are off again.
+    bsp::mmu::enable();
+
+    let mut settings = loader::Settings::new();
+    let mut image = loader::Image::legacy(loader::LOAD_ADDR, 0);
+
+    let throughput = 'handshake: loop {
+        log::set_quiet(false);
+
+        print!("RBIN64\n\x03\x03\x03");
+
+        // Handle commands until one of them starts an upload
+        loop {
+            // A host that stops halfway through a command gets the handshake
+            // again, that way both sides are back in sync
+            let word = match protocol::read_command(&uart) {
+                Ok(word) => word,
+                Err(_) => continue 'handshake,
+            };
+
+            let command = match Command::from(word) {
+                Some(command) => command,
+                None => {
+                    let legacy = loader::Image::legacy(settings.load_addr, word);
+                    if legacy.fits(&bounds) {
+                        image = legacy;
+                        break;
+                    }
+
+                    // A legacy host gives up on anything but OK
+                    protocol::reply(&uart, "ER");
+                    continue;
+                }
+            };
+
+            let mut args = [0; protocol::MAX_ARGS];
+            if protocol::read_args(&uart, &mut args[..command.args()]).is_err() {
+                continue 'handshake;
+            }
 
-    let mut size: u32 = u32::from(uart.getc());
-    size |= u32::from(uart.getc()) << 8;
-    size |= u32::from(uart.getc()) << 16;
-    size |= u32::from(uart.getc()) << 24;
+            match command {
+                Command::Reset => {
+                    protocol::reply(&uart, "OK");
+                    uart.flush();
+                    watchdog.reset();
+                }
+                Command::TransferTimeout => {
+                    // The host has to know it did not get what it asked for. The
+                    // watchdog only matters if it fires before the stall timeout.
+                    if args[0] <= bsp::Watchdog::MAX_TIMEOUT_SECS
+                        && u64::from(args[0]) < loader::STALL_TIMEOUT.as_secs()
+                    {
+                        settings.transfer_timeout = args[0];
+                        protocol::reply(&uart, "OK");
+                    } else {
+                        protocol::reply(&uart, "ER");
+                    }
+                }
+                Command::Progress => {
+                    settings.progress_interval = args[0].saturating_mul(1024);
+                    protocol::reply(&uart, "OK");
+                }
+                Command::BoardInfo => {
+                    protocol::reply(&uart, "OK");
+                    board_info.send(&uart);
+                }
+                Command::LineErrors => {
+                    let errors = uart.line_errors();
 
-    uart.send('O');
-    uart.send('K');
+                    protocol::reply(&uart, "OK");
+                    protocol::send_u32(&uart, errors.framing);
+                    protocol::send_u32(&uart, errors.parity);
+                    protocol::send_u32(&uart, errors.breaks);
+                    protocol::send_u32(&uart, errors.overruns);
+                }
+                Command::FlowControl => match args[0] {
+                    0 => {
+                        protocol::reply(&uart, "OK");
+                        uart.set_flow_control(false);
+                    }
+                    1 => {
+                        protocol::reply(&uart, "OK");
+                        uart.set_flow_control(true);
+                    }
+                    _ => protocol::reply(&uart, "ER"),
+                },
+                Command::SoftwareFlowControl => match args[0] {
+                    0 | 1 => {
+                        uart.set_software_flow_control(args[0] == 1);
+                        protocol::reply(&uart, "OK");
+                    }
+                    _ => protocol::reply(&uart, "ER"),
+                },
+                Command::HandoffLevel => match bsp::ExceptionLevel::from(args[0]) {
+                    Some(level) => {
+                        settings.handoff_level = level;
+                        protocol::reply(&uart, "OK");
+                    }
+                    None => protocol::reply(&uart, "ER"),
+                },
+                Command::Diagnostics => match diag::Mode::from(args[0]) {
+                    Some(mode) => {
+                        protocol::reply(&uart, "OK");
+                        diag::run(&uart, mode, args[1], args[2]);
+                    }
+                    None => protocol::reply(&uart, "ER"),
+                },
+                Command::LoadAddress => {
+                    // The size is not known yet, every upload is checked again
+                    let addr = args[0] as usize;
+                    if addr % 4 == 0 && loader::fits(addr, 1, &bounds) {
+                        settings.load_addr = addr;
+                        protocol::reply(&uart, "OK");
+                    } else {
+                        protocol::reply(&uart, "ER");
+                    }
+                }
+                Command::Load | Command::Delta => {
+                    let kind = match command {
+                        Command::Load => loader::Kind::Full,
+                        _ => loader::Kind::Delta,
+                    };
 
-    let kernel_addr: *mut u8 = 0x80_000 as *mut u8;
-    unsafe {
-        for i in 0..size {
-            *kernel_addr.offset(i as isize) = uart.getc();
+                    // Whatever was received before stays resumable
+                    let upload = loader::Image::new(kind, args[0], settings.load_addr, args[1]);
+                    if upload.fits(&bounds) {
+                        image = upload;
+                        break;
+                    }
+
+                    protocol::reply(&uart, "ER");
+                }
+                Command::Resume => {
+                    let (id, offset) = (args[0], args[1]);
+
+                    match image.resume(id, offset) {
+                        Ok(()) => break,
+                        Err(loader::ResumeError::UnknownImage) => protocol::reply(&uart, "ER"),
+                        Err(loader::ResumeError::Behind(committed)) => {
+                            protocol::reply(&uart, "RE");
+                            protocol::send_u32(&uart, committed);
+                        }
+                    }
+                }
+            }
+        }
+
+        uart.send('O');
+        uart.send('K');
+
+        // From here on the host sends binary data, or waits for it
+        log::set_quiet(true);
+
+        // The host may ask for the watchdog as a last resort, otherwise a
+        // host that goes away halfway through gets the handshake again after
+        // loader::STALL_TIMEOUT
+        settings.arm_watchdog(&mut watchdog);
+        let received = loader::receive(&uart, &timer, &watchdog, &settings, &mut image);
+        watchdog.stop();
+
+        // A stalled upload keeps what it has so far, the host can resume it
+        // after the next handshake. Delta uploads have to start over.
+        if let Err(loader::Error::Stalled) = received {
+            log::set_quiet(false);
+            warn!("The upload stalled, back to the handshake");
+            continue;
+        }
+
+        if image.kind() == loader::Kind::Legacy {
+            // There is no way to tell a legacy host, but it sends the image
+            // again when it sees the handshake
+            match received {
+                Ok(throughput) => break Some(throughput),
+                Err(loader::Error::Line(e)) => {
+                    log::set_quiet(false);
+                    warn!("The UART flagged a bad byte ({:?}), starting over", e);
+                    continue;
+                }
+                Err(_) => continue,
+            }
         }
+
+        // So does one the UART flagged a bad byte in, the host learns how far
+        // it got
+        let throughput = match received {
+            Ok(throughput) => throughput,
+            Err(loader::Error::Line(_)) => {
+                protocol::reply(&uart, "LE");
+                protocol::send_u32(&uart, image.committed(image.id()));
+                continue;
+            }
+            Err(_) => continue,
+        };
+
+        // Without the CRC the image stays committed, resuming at its end
+        // asks for it again
+        let crc = match protocol::read_u32(&uart) {
+            Ok(crc) => crc,
+            Err(_) => continue,
+        };
+
+        if image.verify(crc) {
+            protocol::reply(&uart, "OK");
+            break Some(throughput);
+        }
+
+        image.discard();
+        protocol::reply(&uart, "CE");
+    };
+
+    log::set_quiet(false);
+
+    if let Some(throughput) = throughput {
+        print!("\n{}\n", throughput);
+    }
+    info!("Starting the kernel at {:#x}", image.addr());
+    uart.flush();
+
+    // The kernel starts out polling the UART with IRQs masked, the way the
+    // firmware would have left things
+    bsp::exception::mask_irqs();
+    uart.set_interrupts(false);
+
+    bsp::mmu::disable();
+
+    // Point the kernel at the spin table for every core that is parked there
+    if let Some(fdt) = device_tree.as_mut() {
+        fdt.set_cpu_release_addrs(bsp::release_addr);
+    }
+
+    let boot_info = boot_info::BootInfo {
+        board: &board_info,
+        command_line: &command_line[..command_line_len],
+        uart: boot_info::UartConfig {
+            number: bsp::UART_NUMBER,
+            clock: uart.clock(),
+            baud_rate: bsp::BAUD_RATE,
+        },
+        image: &image,
+        device_tree: device_tree.as_ref(),
+        exception_level: bsp::handoff_level(settings.handoff_level) as u32,
+        timer_frequency: timer.frequency(),
+        start_ticks,
+    };
+    let boot_info = boot_info.write(timer.ticks());
+    let boot_info_addr = boot_info.as_ptr() as usize;
+
+    // The kernel may turn the caches on first thing, so everything we handed
+    // it has to be in memory, not just in the data cache. Our own state goes
+    // too, the parked cores keep reading the spin table.
+    bsp::cache::clean_invalidate_range(image.addr(), image.size() as usize);
+    bsp::cache::clean_range(boot_info_addr, boot_info.len() * 4);
+    if let Some(fdt) = device_tree.as_ref() {
+        bsp::cache::clean_range(fdt.addr(), fdt.size());
     }
+    bsp::cache::clean_invalidate_all();
+    bsp::cache::barrier();
 
-    let kernel: extern "C" fn() -> ! = unsafe { core::mem::transmute(kernel_addr as *const ()) };
-    kernel()
+    // The device tree goes first so Linux style kernels find it, the boot
+    // information takes its place when there is none
+    let x0 = device_tree
+        .as_ref()
+        .map_or(boot_info_addr, |fdt| fdt.addr());
+    unsafe { bsp::jump_to_kernel(image.addr(), x0, boot_info_addr, settings.handoff_level) }
 }
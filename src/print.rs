@@ -0,0 +1,43 @@
+//! Printing to the UART.
+
+use crate::bsp::{self, SoftwareFlow};
+use core::fmt::{self, Write};
+
+/// Allows the `write!` family of macros to be used on the UART. Newlines are
+/// sent as `\r\n` so terminals on the host side line up. This is the only way
+/// text goes out, so XON/XOFF flow control applies here and nowhere else.
+impl Write for bsp::Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.software_flow_control(|| {
+            for c in s.chars() {
+                if c == '\n' {
+                    self.send('\r');
+                }
+
+                self.send(c);
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    bsp::Uart::new().write_fmt(args).ok();
+}
+
+/// Prints without a newline, `\n` goes out as `\r\n`.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::print::_print(format_args!($($arg)*)));
+}
+
+/// Prints with a newline.
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ({
+        $crate::print::_print(format_args_nl!($($arg)*));
+    })
+}
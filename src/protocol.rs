@@ -0,0 +1,193 @@
+//! The serial protocol spoken with the host.
+//!
+//! After the `\x03\x03\x03` break the host sends little endian 32-bit words.
+//! A word below `COMMAND_BASE` is the size of a kernel to upload, exactly like
+//! the original raspbootin protocol, answered with `ER` instead of `OK` if it
+//! does not fit at the load address. Anything at or above it is a command,
+//! which is acknowledged with `OK` before the host sends the next word. The
+//! handshake starts over whenever an upload fails, and whenever the host goes
+//! quiet for `BYTE_TIMEOUT` in the middle of a word.
+
+use crate::bsp::{Receive, RxError, SoftwareFlow, Uart, Watchdog};
+use core::time::Duration;
+
+const COMMAND_BASE: u32 = 0xFFFF_FF00;
+
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum Command {
+    /// Reset the board through the watchdog, which boots back into raspbootin
+    Reset = COMMAND_BASE,
+    /// Followed by a word with the number of seconds after which the watchdog
+    /// resets the board if an upload gets no further. 0, the default, leaves
+    /// it to `loader::STALL_TIMEOUT`, after which any upload drops back to
+    /// the handshake, a `Load` so it can be resumed. `ER` if it is more than
+    /// the watchdog can count, `Watchdog::MAX_TIMEOUT_SECS`, or not shorter
+    /// than `loader::STALL_TIMEOUT`, which would always get there first.
+    TransferTimeout = COMMAND_BASE + 1,
+    /// Followed by a word with an interval in KiB. During the upload
+    /// `PROGRESS_MARKER` is sent every time that much has been received. 0
+    /// disables the markers, which is the default.
+    Progress = COMMAND_BASE + 2,
+    /// Followed by the `diag::Mode`, a length and a seed word. Runs the link
+    /// test or the UART self-test and sends its report, then the handshake
+    /// carries on.
+    Diagnostics = COMMAND_BASE + 3,
+    /// Followed by an image id and the size. After the image the host sends
+    /// its CRC-32, which is answered with `OK` or `CE`. If the UART flags a
+    /// receive error the board stops taking the image, waits for the host to
+    /// go quiet and answers `LE` and the number of bytes it has. `ER` instead
+    /// of `OK` if the image does not fit at the load address, see
+    /// `loader::fits`.
+    Load = COMMAND_BASE + 4,
+    /// Followed by an image id and an offset, continues a `Load` from there.
+    /// If we do not have that much of the image the reply is `RE` and the
+    /// number of bytes we do have. If the last upload was not a `Load` or
+    /// `Delta` with that id the reply is `ER`.
+    Resume = COMMAND_BASE + 5,
+    /// Followed by an image id and the size. Like `Load`, but only the blocks
+    /// that differ from what is in memory are sent, see `loader`.
+    Delta = COMMAND_BASE + 6,
+    /// Sends the `board_info` record. Meant to be the first thing a host does
+    /// after the handshake. It is not sent unasked, an original raspbootin
+    /// host would take it for the reply to the kernel size.
+    BoardInfo = COMMAND_BASE + 7,
+    /// Followed by the exception level to enter the kernel at, 1 or 2. The
+    /// default depends on the `handoff_el1` feature.
+    HandoffLevel = COMMAND_BASE + 8,
+    /// Sends the number of framing, parity, break and overrun errors the UART
+    /// has flagged since boot, a word each.
+    LineErrors = COMMAND_BASE + 9,
+    /// Followed by 1 to turn RTS/CTS flow control on and 0 to turn it off.
+    /// The `OK` is sent before the switch, the host changes its own setting
+    /// once it has that. The default depends on the `uart_flow_control`
+    /// feature.
+    FlowControl = COMMAND_BASE + 10,
+    /// Followed by the address to put the following uploads at and start them
+    /// from, `loader::LOAD_ADDR` by default. `ER` if it is not word aligned
+    /// or lies in memory an upload must not touch, see `loader::fits`.
+    LoadAddress = COMMAND_BASE + 11,
+    /// Followed by 1 to turn XON/XOFF flow control on and 0 to turn it off.
+    /// It only applies to the text the board prints, the handshake banner and
+    /// log messages, everything else is binary. Meanwhile the board sends
+    /// XOFF once its receive buffer is three quarters full and XON once it is
+    /// down to a quarter. The default depends on the
+    /// `uart_software_flow_control` feature.
+    SoftwareFlowControl = COMMAND_BASE + 12,
+}
+
+/// How long the host may take for each byte once it has started a word
+pub const BYTE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The most words any command is followed by
+pub const MAX_ARGS: usize = 3;
+
+/// Sent during an upload when progress markers are enabled
+pub const PROGRESS_MARKER: char = '\x06';
+
+// This is a hack because we are on no_std
+impl Command {
+    pub fn from(value: u32) -> Option<Command> {
+        match value {
+            v if v == Command::Reset as u32 => Some(Command::Reset),
+            v if v == Command::TransferTimeout as u32 => Some(Command::TransferTimeout),
+            v if v == Command::Progress as u32 => Some(Command::Progress),
+            v if v == Command::Diagnostics as u32 => Some(Command::Diagnostics),
+            v if v == Command::Load as u32 => Some(Command::Load),
+            v if v == Command::Resume as u32 => Some(Command::Resume),
+            v if v == Command::Delta as u32 => Some(Command::Delta),
+            v if v == Command::BoardInfo as u32 => Some(Command::BoardInfo),
+            v if v == Command::HandoffLevel as u32 => Some(Command::HandoffLevel),
+            v if v == Command::LineErrors as u32 => Some(Command::LineErrors),
+            v if v == Command::FlowControl as u32 => Some(Command::FlowControl),
+            v if v == Command::LoadAddress as u32 => Some(Command::LoadAddress),
+            v if v == Command::SoftwareFlowControl as u32 => Some(Command::SoftwareFlowControl),
+            _ => None,
+        }
+    }
+
+    /// How many words follow the command
+    pub fn args(self) -> usize {
+        match self {
+            Command::Reset | Command::BoardInfo | Command::LineErrors => 0,
+            Command::TransferTimeout
+            | Command::Progress
+            | Command::HandoffLevel
+            | Command::FlowControl
+            | Command::LoadAddress
+            | Command::SoftwareFlowControl => 1,
+            Command::Load | Command::Resume | Command::Delta => 2,
+            Command::Diagnostics => 3,
+        }
+    }
+}
+
+/// The host went quiet halfway through a word
+#[derive(Debug)]
+pub struct Timeout;
+
+/// Wait for as long as it takes for the host to start a word, then receive it
+/// like `read_u32`
+pub fn read_command(uart: &Uart) -> Result<u32, Timeout> {
+    let mut value = u32::from(uart.getc());
+    for i in 1..4 {
+        value |= u32::from(read_byte(uart)?) << (i * 8);
+    }
+
+    Ok(value)
+}
+
+/// Receive a little endian word, giving up if the host takes longer than
+/// `BYTE_TIMEOUT` for any of its bytes
+pub fn read_u32(uart: &Uart) -> Result<u32, Timeout> {
+    let mut value = 0;
+    for i in 0..4 {
+        value |= u32::from(read_byte(uart)?) << (i * 8);
+    }
+
+    Ok(value)
+}
+
+/// Receive the words following a command, see `Command::args`
+pub fn read_args(uart: &Uart, args: &mut [u32]) -> Result<(), Timeout> {
+    for arg in args.iter_mut() {
+        *arg = read_u32(uart)?;
+    }
+
+    Ok(())
+}
+
+// Bad bytes are passed on as they are, the line errors are counted by the UART
+fn read_byte(uart: &Uart) -> Result<u8, Timeout> {
+    match uart.getc_timeout(BYTE_TIMEOUT) {
+        Ok(c) => Ok(c),
+        Err(RxError::Empty) => Err(Timeout),
+        Err(e) => Ok(e.data().unwrap_or(0)),
+    }
+}
+
+/// Send a little endian word
+pub fn send_u32(uart: &Uart, value: u32) {
+    for i in 0..4 {
+        uart.send((value >> (i * 8)) as u8 as char);
+    }
+}
+
+/// Send a two character status reply such as `OK`
+pub fn reply(uart: &Uart, status: &str) {
+    for c in status.chars() {
+        uart.send(c);
+    }
+}
+
+/// Ignore everything but `Command::Reset`, for when something went wrong and
+/// the handshake is no longer an option
+pub fn wait_for_reset(uart: &Uart) -> ! {
+    loop {
+        if let Ok(Some(Command::Reset)) = read_command(uart).map(Command::from) {
+            reply(uart, "OK");
+            uart.flush();
+            Watchdog::new().reset();
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! A single producer, single consumer ring buffer for handing received
+//! characters from an interrupt handler to the code it interrupted.
+//!
+//! Both ends run on the same core, so all the ordering needed is that the
+//! compiler writes an entry before publishing it. That keeps it lock-free
+//! without atomics, which are not available while the MMU is off.
+
+use core::cell::UnsafeCell;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{compiler_fence, Ordering};
+
+/// Number of entries, a power of two
+pub const CAPACITY: usize = 4096;
+
+pub struct RingBuffer {
+    data: UnsafeCell<[u16; CAPACITY]>,
+    /// Free running count of entries pushed, only written by the producer
+    head: UnsafeCell<usize>,
+    /// Free running count of entries popped, only written by the consumer
+    tail: UnsafeCell<usize>,
+}
+
+// The producer and the consumer only ever write their own index
+unsafe impl Sync for RingBuffer {}
+
+impl RingBuffer {
+    pub const fn new() -> RingBuffer {
+        RingBuffer {
+            data: UnsafeCell::new([0; CAPACITY]),
+            head: UnsafeCell::new(0),
+            tail: UnsafeCell::new(0),
+        }
+    }
+
+    /// Producer side. Fails if the buffer is full.
+    pub fn push(&self, value: u16) -> Result<(), ()> {
+        let head = unsafe { read_volatile(self.head.get()) };
+        let tail = unsafe { read_volatile(self.tail.get()) };
+
+        if head.wrapping_sub(tail) == CAPACITY {
+            return Err(());
+        }
+
+        unsafe {
+            write_volatile(&mut (*self.data.get())[head % CAPACITY], value);
+            compiler_fence(Ordering::Release);
+            write_volatile(self.head.get(), head.wrapping_add(1));
+        }
+
+        Ok(())
+    }
+
+    /// Consumer side
+    pub fn pop(&self) -> Option<u16> {
+        let head = unsafe { read_volatile(self.head.get()) };
+        let tail = unsafe { read_volatile(self.tail.get()) };
+
+        if head == tail {
+            return None;
+        }
+
+        compiler_fence(Ordering::Acquire);
+        unsafe {
+            let value = read_volatile(&(*self.data.get())[tail % CAPACITY]);
+            write_volatile(self.tail.get(), tail.wrapping_add(1));
+
+            Some(value)
+        }
+    }
+
+    /// How many entries are waiting, for either side
+    pub fn len(&self) -> usize {
+        unsafe { read_volatile(self.head.get()).wrapping_sub(read_volatile(self.tail.get())) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        unsafe { read_volatile(self.head.get()) == read_volatile(self.tail.get()) }
+    }
+}
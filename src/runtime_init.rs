@@ -1,5 +1,5 @@
 #[no_mangle]
-pub unsafe fn init() -> ! {
+pub unsafe extern "C" fn init(device_tree: u64) -> ! {
     extern "C" {
         static mut __bss_start: u64;
         static mut __bss_end: u64;
@@ -7,5 +7,5 @@ pub unsafe fn init() -> ! {
 
     r0::zero_bss(&mut __bss_start, &mut __bss_end);
 
-    crate::kernel_entry()
+    crate::kernel_entry(device_tree as usize)
 }